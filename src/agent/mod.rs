@@ -0,0 +1,1448 @@
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use serde_json::json;
+use tokio::sync::{broadcast, mpsc, watch};
+
+use crate::api::auth::{is_otp_rejected, is_token_expired, refresh_token, verify_otp};
+use crate::api::session::get_session_info;
+use crate::api::state::get_session_state;
+use crate::audit::{AuditLog, AuditedController, CommandSource};
+use crate::cache::{self, CacheWriter, CachedSession};
+use crate::connection::{ConnectionPhase, ConnectionState, FailureKind};
+use crate::controller::{
+    build_controller, execute, Controller, ControllerBackend, Navigation, PausableController,
+};
+use crate::eventlog::{LogCategory, LogEntry, LogLevel};
+use crate::metrics::Metrics;
+use crate::models::auth::{RefreshTokenResponse, VerifyOtpResponse};
+use crate::models::events::Event;
+use crate::models::session::{SessionInfo, SessionInfoPage};
+use crate::models::state::SessionState;
+use crate::models::websocket::{ChangeCurrentPageRequestData, WsRequest};
+use crate::net::{build_http_client, NetworkSettings};
+use crate::polling::{diff_positions, run_polling, PollHandle, PollMode, PollTarget, Position};
+use crate::settings::ConnectionProfile;
+use crate::timing::PresentationTimer;
+use crate::websocket::sequence::SequenceTracker;
+use crate::websocket::{run_websocket, WsHandle, WsTarget};
+
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+const WS_RETRY_INTERVAL: Duration = Duration::from_secs(10);
+const VOTE_POLL_INTERVAL: Duration = Duration::from_secs(2);
+/// 位置やデッキの変化をまとめてからキャッシュに書くまでの待ち時間
+const PERSIST_DELAY: Duration = Duration::from_secs(2);
+/// トークンの有効期限を確かめる間隔
+const TICK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// 失効の少し前にリフレッシュを始める
+const TOKEN_REFRESH_MARGIN: Duration = Duration::from_secs(30);
+
+/// 購読者が読み遅れたときに溜めておくイベントの数
+const EVENT_BUFFER: usize = 256;
+
+/// フロントエンドからの操作
+#[derive(Debug)]
+pub enum Command {
+    Connect { profile: ConnectionProfile, otp: String },
+    /// 前回のセッションへ戻る。サーバーとエージェント名はキャッシュのものを使う
+    ResumeLastSession { profile: ConnectionProfile },
+    Retry { otp: String },
+    Cancel,
+    Disconnect,
+    /// 手元のトークンのまま接続を張り直す
+    Reconnect,
+    Reauthenticate { otp: String },
+    /// 止めている間もセッションの位置は追い、スライドだけ動かさない
+    PauseActuation { paused: bool },
+    Navigate { navigation: Navigation, upstream: bool },
+}
+
+/// 状態の写しでは表せない、フロントエンドに一度だけ伝えること
+#[derive(Debug, Clone)]
+pub enum Notification {
+    Status(String),
+    Log(LogEntry),
+}
+
+/// フロントエンドが描画に使う状態の写し。変わるたびに作り直して配る
+#[derive(Debug, Clone, Default)]
+pub struct Snapshot {
+    pub phase: ConnectionPhase,
+    pub reauth_required: bool,
+    pub slide_name: String,
+    pub current_slide_index: usize,
+    pub total_slide_count: usize,
+    pub current_step: usize,
+    pub pages: Vec<SessionInfoPage>,
+    pub session_info: Option<SessionInfo>,
+    pub active_vote_ids: Vec<String>,
+    /// vote_id -> choice_id -> 票数
+    pub vote_tallies: HashMap<String, HashMap<String, i32>>,
+    pub timer: PresentationTimer,
+    pub ws_connected: bool,
+    pub actuation_paused: bool,
+    pub session_id: String,
+    pub controller_backend: ControllerBackend,
+    pub resume_candidate: Option<CachedSession>,
+}
+
+/// 中核への窓口。操作を送り、最新の状態と通知を受け取る
+pub struct AgentHandle {
+    commands: mpsc::UnboundedSender<Command>,
+    snapshot: watch::Receiver<Arc<Snapshot>>,
+    events: broadcast::Sender<Event>,
+    metrics: Arc<Metrics>,
+    notifications: mpsc::UnboundedReceiver<Notification>,
+}
+
+/// 通知を受け取らない、複製できる窓口。制御ソケットなど別のタスクから使う
+#[derive(Clone)]
+pub struct AgentClient {
+    commands: mpsc::UnboundedSender<Command>,
+    snapshot: watch::Receiver<Arc<Snapshot>>,
+    events: broadcast::Sender<Event>,
+    metrics: Arc<Metrics>,
+}
+
+impl AgentClient {
+    /// 中核が止まっていたら`false`
+    pub fn send(&self, command: Command) -> bool {
+        self.commands.send(command).is_ok()
+    }
+
+    pub fn snapshot(&self) -> Arc<Snapshot> {
+        self.snapshot.borrow().clone()
+    }
+
+    /// 中核が処理したイベントを購読する
+    pub fn events(&self) -> broadcast::Receiver<Event> {
+        self.events.subscribe()
+    }
+
+    pub fn metrics(&self) -> &Metrics {
+        &self.metrics
+    }
+}
+
+impl AgentHandle {
+    pub fn send(&self, command: Command) {
+        if self.commands.send(command).is_err() {
+            log::error!("Agent core has stopped");
+        }
+    }
+
+    pub fn snapshot(&self) -> Arc<Snapshot> {
+        self.snapshot.borrow().clone()
+    }
+
+    /// 状態が変わるのを待ちたいとき用
+    pub fn subscribe(&self) -> watch::Receiver<Arc<Snapshot>> {
+        self.snapshot.clone()
+    }
+
+    pub fn try_recv(&mut self) -> Option<Notification> {
+        self.notifications.try_recv().ok()
+    }
+
+    pub async fn recv(&mut self) -> Option<Notification> {
+        self.notifications.recv().await
+    }
+
+    pub fn client(&self) -> AgentClient {
+        AgentClient {
+            commands: self.commands.clone(),
+            snapshot: self.snapshot.clone(),
+            events: self.events.clone(),
+            metrics: self.metrics.clone(),
+        }
+    }
+}
+
+/// 非同期処理の結果。中核のタスクに戻してから状態に反映する
+enum Internal {
+    OtpVerified {
+        attempt: u64,
+        result: anyhow::Result<VerifyOtpResponse>,
+    },
+    SessionInfoFetched {
+        attempt: u64,
+        result: anyhow::Result<SessionInfo>,
+    },
+    SessionStateFetched {
+        attempt: u64,
+        result: anyhow::Result<SessionState>,
+    },
+    WsConnected {
+        attempt: u64,
+        result: anyhow::Result<WsHandle>,
+    },
+    WsRetryDue {
+        attempt: u64,
+    },
+    TokenRefreshed {
+        attempt: u64,
+        result: anyhow::Result<RefreshTokenResponse>,
+    },
+    Reauthenticated {
+        attempt: u64,
+        result: anyhow::Result<VerifyOtpResponse>,
+    },
+    StateRefreshed {
+        attempt: u64,
+        result: anyhow::Result<SessionState>,
+    },
+    Resynced {
+        attempt: u64,
+        result: anyhow::Result<SessionState>,
+    },
+    PersistDue,
+}
+
+/// セッションの状態を一手に持つ中核を`runtime`上で動かす。状態が変わるたびに`on_change`を呼ぶ
+pub fn spawn(
+    runtime: &tokio::runtime::Handle,
+    audit: AuditLog,
+    on_change: impl Fn() + Send + Sync + 'static,
+) -> AgentHandle {
+    let (command_tx, command_rx) = mpsc::unbounded_channel();
+    let (notification_tx, notification_rx) = mpsc::unbounded_channel();
+    let (event_tx, event_rx) = mpsc::unbounded_channel();
+    let (internal_tx, internal_rx) = mpsc::unbounded_channel();
+    let (snapshot_tx, snapshot_rx) = watch::channel(Arc::new(Snapshot::default()));
+    let (event_broadcast, _) = broadcast::channel(EVENT_BUFFER);
+    let metrics = Arc::new(Metrics::default());
+
+    let agent = Agent {
+        profile: ConnectionProfile::default(),
+        session_server_address: String::new(),
+        session_id: String::new(),
+        token: String::new(),
+        refresh_token: None,
+        token_expires_at: None,
+        refreshing_token: false,
+        reauth_required: false,
+        connection: ConnectionState::default(),
+        session_info: None,
+        current_slide_index: 0,
+        current_step: 0,
+        active_vote_ids: Vec::new(),
+        vote_tallies: HashMap::new(),
+        timer: PresentationTimer::default(),
+        ws_handle: None,
+        ws_connecting: false,
+        poll_handle: None,
+        vote_watch_handle: None,
+        sequence_tracker: Arc::default(),
+        resyncing: false,
+        resume_candidate: cache::load(),
+        cache: CacheWriter::default(),
+        persist_scheduled: false,
+        actuation_paused: Arc::default(),
+        event_tx,
+        event_broadcast: event_broadcast.clone(),
+        metrics: metrics.clone(),
+        audit: Arc::new(audit),
+        connect_started_at: None,
+        internal_tx,
+        notification_tx,
+        snapshot_tx,
+        on_change: Box::new(on_change),
+    };
+    agent.publish();
+    runtime.spawn(agent.run(command_rx, event_rx, internal_rx));
+
+    AgentHandle {
+        commands: command_tx,
+        snapshot: snapshot_rx,
+        events: event_broadcast,
+        metrics,
+        notifications: notification_rx,
+    }
+}
+
+struct Agent {
+    /// 接続に使った設定。サーバー・エージェント名・操作方法・ネットワーク
+    profile: ConnectionProfile,
+    session_server_address: String,
+    session_id: String,
+    token: String,
+    refresh_token: Option<String>,
+    token_expires_at: Option<Instant>,
+    refreshing_token: bool,
+    reauth_required: bool,
+    connection: ConnectionState,
+    session_info: Option<SessionInfo>,
+    current_slide_index: usize,
+    current_step: usize,
+    active_vote_ids: Vec<String>,
+    vote_tallies: HashMap<String, HashMap<String, i32>>,
+    timer: PresentationTimer,
+    ws_handle: Option<WsHandle>,
+    ws_connecting: bool,
+    poll_handle: Option<PollHandle>,
+    vote_watch_handle: Option<PollHandle>,
+    /// 再接続をまたいで通し番号を追うため接続ごとではなくここで持つ
+    sequence_tracker: Arc<Mutex<SequenceTracker>>,
+    resyncing: bool,
+    resume_candidate: Option<CachedSession>,
+    cache: CacheWriter,
+    /// 位置などの書き込みを待っている。ページ送りのたびには書かない
+    persist_scheduled: bool,
+    /// 操作の実行側と共有し、切り替えを実行中のタスクにもすぐ効かせる
+    actuation_paused: Arc<AtomicBool>,
+    /// WebSocketとポーリングで同じチャンネルを共有し、どちらから来たか気にしない
+    event_tx: mpsc::UnboundedSender<Event>,
+    event_broadcast: broadcast::Sender<Event>,
+    metrics: Arc<Metrics>,
+    /// 切断をまたいでプロセスが終わるまで同じファイルに書き続ける
+    audit: Arc<AuditLog>,
+    /// 接続手順を始めた時刻。つながったら所要時間として記録する
+    connect_started_at: Option<Instant>,
+    internal_tx: mpsc::UnboundedSender<Internal>,
+    notification_tx: mpsc::UnboundedSender<Notification>,
+    snapshot_tx: watch::Sender<Arc<Snapshot>>,
+    on_change: Box<dyn Fn() + Send + Sync>,
+}
+
+impl Agent {
+    async fn run(
+        mut self,
+        mut commands: mpsc::UnboundedReceiver<Command>,
+        mut events: mpsc::UnboundedReceiver<Event>,
+        mut internal: mpsc::UnboundedReceiver<Internal>,
+    ) {
+        let mut ticker = tokio::time::interval(TICK_INTERVAL);
+        loop {
+            tokio::select! {
+                command = commands.recv() => match command {
+                    Some(command) => self.handle_command(command),
+                    // フロントエンドが閉じたら終わる
+                    None => break,
+                },
+                Some(event) = events.recv() => self.handle_event(event),
+                Some(message) = internal.recv() => self.handle_internal(message),
+                _ = ticker.tick() => {
+                    if !self.check_token_expiry() {
+                        continue;
+                    }
+                }
+            }
+            // WebSocketでもポーリングでも、つながった時点から計測を始める
+            if self.connection.phase().is_connected() {
+                self.timer.start(self.current_slide_index);
+                if let Some(started_at) = self.connect_started_at.take() {
+                    self.metrics.connect_duration.observe(started_at.elapsed());
+                }
+            }
+            self.publish();
+        }
+        self.shutdown_transports();
+    }
+
+    fn publish(&self) {
+        let pages = self
+            .session_info
+            .as_ref()
+            .map(|info| info.pages.clone())
+            .unwrap_or_default();
+        let snapshot = Snapshot {
+            phase: self.connection.phase().clone(),
+            reauth_required: self.reauth_required,
+            slide_name: self
+                .session_info
+                .as_ref()
+                .map(|info| info.title.clone())
+                .unwrap_or_default(),
+            current_slide_index: self.current_slide_index,
+            total_slide_count: pages.len(),
+            current_step: self.current_step,
+            pages,
+            session_info: self.session_info.clone(),
+            active_vote_ids: self.active_vote_ids.clone(),
+            vote_tallies: self.vote_tallies.clone(),
+            timer: self.timer.clone(),
+            ws_connected: self.ws_handle.is_some(),
+            actuation_paused: self.actuation_paused.load(Ordering::Relaxed),
+            session_id: self.session_id.clone(),
+            controller_backend: self.profile.controller_backend,
+            resume_candidate: self.resume_candidate.clone(),
+        };
+        self.snapshot_tx.send_replace(Arc::new(snapshot));
+        (self.on_change)();
+    }
+
+    fn status(&self, message: impl Into<String>) {
+        let _ = self
+            .notification_tx
+            .send(Notification::Status(message.into()));
+    }
+
+    fn log(
+        &self,
+        level: LogLevel,
+        category: LogCategory,
+        message: impl Into<String>,
+        payload: Option<serde_json::Value>,
+    ) {
+        let entry = LogEntry::new(level, category, message, payload);
+        let _ = self.notification_tx.send(Notification::Log(entry));
+    }
+
+    /// 非同期処理を走らせ、終わったら結果をこのタスクに戻す
+    fn spawn_task(&self, task: impl Future<Output = Internal> + Send + 'static) {
+        let internal_tx = self.internal_tx.clone();
+        tokio::spawn(async move {
+            let _ = internal_tx.send(task.await);
+        });
+    }
+
+    fn handle_command(&mut self, command: Command) {
+        match command {
+            Command::Connect { profile, otp } => self.connect_to_session(profile, otp),
+            Command::ResumeLastSession { profile } => self.resume_last_session(profile),
+            Command::Retry { otp } => self.retry_connection(otp),
+            Command::Cancel => self.cancel_connection(),
+            Command::Disconnect => self.disconnect(),
+            Command::Reconnect => self.reconnect(),
+            Command::Reauthenticate { otp } => self.reauthenticate(otp),
+            Command::PauseActuation { paused } => self.pause_actuation(paused),
+            Command::Navigate {
+                navigation,
+                upstream,
+            } => {
+                if upstream {
+                    self.navigate_upstream(navigation);
+                } else {
+                    self.navigate_locally(navigation);
+                }
+            }
+        }
+    }
+
+    fn handle_event(&mut self, event: Event) {
+        // 購読者がいなければ送れないだけなので気にしない
+        let _ = self.event_broadcast.send(event.clone());
+        match event {
+            Event::ConnectionEstablished => {
+                self.status("WebSocket connected");
+            }
+            Event::SlideChanged { new_page_index } => {
+                self.current_slide_index = new_page_index;
+                self.timer.on_page_changed(new_page_index);
+                self.log(
+                    LogLevel::Info,
+                    LogCategory::Actuator,
+                    format!("ページを{}に変更しました", new_page_index),
+                    Some(json!({ "page_index": new_page_index })),
+                );
+                self.schedule_persist();
+            }
+            Event::StepChanged {
+                new_page_index,
+                new_step_index,
+            } => {
+                self.current_slide_index = new_page_index;
+                self.current_step = new_step_index;
+                self.timer.on_page_changed(new_page_index);
+                self.log(
+                    LogLevel::Info,
+                    LogCategory::Actuator,
+                    format!(
+                        "ステップを{}に変更しました (ページ{})",
+                        new_step_index, new_page_index
+                    ),
+                    Some(json!({
+                        "page_index": new_page_index,
+                        "step_index": new_step_index,
+                    })),
+                );
+                self.schedule_persist();
+            }
+            Event::TokenExpired => {
+                self.handle_token_expired();
+            }
+            Event::ConnectionLost => {
+                self.metrics.record_reconnect();
+                self.log(
+                    LogLevel::Warn,
+                    LogCategory::Network,
+                    "WebSocket接続が切れました",
+                    None,
+                );
+                self.handle_connection_lost();
+            }
+            Event::ActuationFailed { action, error } => {
+                self.metrics.record_actuation_failure(&error);
+                self.log(
+                    LogLevel::Error,
+                    LogCategory::Actuator,
+                    format!("{:?} の実行に失敗しました: {}", action, error),
+                    Some(json!({ "action": format!("{:?}", action), "error": error })),
+                );
+            }
+            Event::ResyncRequired => {
+                self.log(
+                    LogLevel::Warn,
+                    LogCategory::Network,
+                    "コマンドの抜けを検出したため再同期します",
+                    None,
+                );
+                self.resync_session_state();
+            }
+            Event::VoteStarted { vote_id } => {
+                self.log(
+                    LogLevel::Info,
+                    LogCategory::Vote,
+                    format!("投票 {} が始まりました", vote_id),
+                    Some(json!({ "vote_id": vote_id })),
+                );
+                if !self.active_vote_ids.contains(&vote_id) {
+                    self.active_vote_ids.push(vote_id);
+                }
+            }
+            Event::VoteClosed { vote_id } => {
+                self.log(
+                    LogLevel::Info,
+                    LogCategory::Vote,
+                    format!("投票 {} が終了しました", vote_id),
+                    Some(json!({ "vote_id": vote_id })),
+                );
+                self.active_vote_ids.retain(|id| id != &vote_id);
+            }
+            Event::VoteTallyChanged {
+                vote_id,
+                choice_votes,
+            } => {
+                self.vote_tallies.insert(vote_id, choice_votes);
+            }
+            Event::Disconnected => {}
+        }
+    }
+
+    fn handle_internal(&mut self, message: Internal) {
+        match message {
+            Internal::OtpVerified { attempt, result } => self.on_otp_verified(attempt, result),
+            Internal::SessionInfoFetched { attempt, result } => {
+                self.on_session_info_fetched(attempt, result)
+            }
+            Internal::SessionStateFetched { attempt, result } => {
+                self.on_session_state_fetched(attempt, result)
+            }
+            Internal::WsConnected { attempt, result } => self.on_ws_connected(attempt, result),
+            Internal::PersistDue => {
+                self.persist_scheduled = false;
+                if let Some(cached) = self.cached_session() {
+                    self.cache.save_in_background(cached);
+                }
+            }
+            Internal::WsRetryDue { attempt } => {
+                // トークンが拒否されている間は張り直さない。更新か再認証の後に張り直す
+                if self.connection.is_current(attempt)
+                    && self.poll_handle.is_some()
+                    && self.ws_handle.is_none()
+                    && !self.refreshing_token
+                    && !self.reauth_required
+                {
+                    self.establish_ws_connection();
+                }
+            }
+            Internal::TokenRefreshed { attempt, result } => {
+                self.on_token_refreshed(attempt, result)
+            }
+            Internal::Reauthenticated { attempt, result } => {
+                self.on_reauthenticated(attempt, result)
+            }
+            Internal::StateRefreshed { attempt, result } => {
+                if !self.connection.is_current(attempt) {
+                    return;
+                }
+                match result {
+                    Ok(response) => {
+                        let current = Position {
+                            page_index: self.current_slide_index,
+                            step_index: self.current_step,
+                        };
+                        // 位置はイベント経由で更新し、購読者にも新しい位置を伝える
+                        let (_, events) = diff_positions(current, Position::from(&response));
+                        for event in events {
+                            let _ = self.event_tx.send(event);
+                        }
+                        self.apply_vote_state(&response);
+                    }
+                    Err(e) if is_token_expired(&e) => self.handle_token_expired(),
+                    Err(e) => self.status(format!("Failed to fetch session state: {}", e)),
+                }
+            }
+            Internal::Resynced { attempt, result } => self.on_resynced(attempt, result),
+        }
+    }
+
+    fn connect_to_session(&mut self, profile: ConnectionProfile, otp: String) {
+        let Some(client) = self.client_for(&profile.network) else {
+            return;
+        };
+        let Some(attempt) = self.connection.begin(ConnectionPhase::VerifyingOtp) else {
+            return;
+        };
+        self.connect_started_at = Some(Instant::now());
+        self.profile = profile;
+        let base_url = self.profile.primary_server_address.clone();
+
+        self.spawn_task(async move {
+            let result = verify_otp(&client, &base_url, &otp).await;
+            Internal::OtpVerified { attempt, result }
+        });
+    }
+
+    fn on_otp_verified(&mut self, attempt: u64, result: anyhow::Result<VerifyOtpResponse>) {
+        if !self.connection.is_current(attempt) {
+            return;
+        }
+        match result {
+            Ok(response) => {
+                self.session_id = response.session_id;
+                self.session_server_address = response.aggregator_url;
+                self.apply_token(response.token, response.expires_in, response.refresh_token);
+                self.reauth_required = false;
+                self.status("OTP verified successfully.");
+                self.connection
+                    .advance(attempt, ConnectionPhase::FetchingInfo);
+                self.fetch_session_info(attempt);
+            }
+            Err(e) => {
+                self.log(
+                    LogLevel::Error,
+                    LogCategory::Auth,
+                    format!("OTPの検証に失敗しました: {}", e),
+                    None,
+                );
+                self.status(format!("OTP Verification Failed: {}", e));
+                // OTPを拒否されたのか、サーバーに届かない・サーバーが不調なのかを分ける
+                let kind = if is_otp_rejected(&e) {
+                    FailureKind::Auth
+                } else {
+                    FailureKind::Network
+                };
+                self.connection
+                    .fail(attempt, kind, format!("OTP Verification Failed: {}", e));
+            }
+        }
+    }
+
+    /// OTPを入れ直さずに、手元のトークンでデッキ情報の取得からやり直す
+    fn start_session_flow(&mut self) {
+        let Some(attempt) = self.connection.begin(ConnectionPhase::FetchingInfo) else {
+            return;
+        };
+        self.connect_started_at = Some(Instant::now());
+        self.fetch_session_info(attempt);
+    }
+
+    /// 接続手順の2段目。キャッシュにデッキがあれば取得に失敗しても先へ進む
+    fn fetch_session_info(&mut self, attempt: u64) {
+        let Some(client) = self.http_client() else {
+            self.connection
+                .fail(attempt, FailureKind::Network, "Invalid network settings.");
+            return;
+        };
+        let base_url = self.session_server_address.clone();
+        let session_id = self.session_id.clone();
+        let token = self.token.clone();
+        self.spawn_task(async move {
+            let result = get_session_info(&client, &base_url, &session_id, &token).await;
+            Internal::SessionInfoFetched { attempt, result }
+        });
+    }
+
+    fn on_session_info_fetched(&mut self, attempt: u64, result: anyhow::Result<SessionInfo>) {
+        if !self.connection.is_current(attempt) {
+            return;
+        }
+        // 取得に失敗しても、別のセッションのデッキで代用はしない
+        if self
+            .session_info
+            .as_ref()
+            .is_some_and(|info| info.session_id != self.session_id)
+        {
+            self.session_info = None;
+        }
+        match result {
+            Ok(response) => {
+                self.session_info = Some(response);
+                self.schedule_persist();
+            }
+            Err(e) if is_token_expired(&e) => {
+                self.connection
+                    .fail(attempt, FailureKind::Auth, "Session token expired.");
+                self.handle_token_expired();
+                return;
+            }
+            Err(e) if self.session_info.is_some() => {
+                self.status(format!(
+                    "Failed to fetch session info, using cached deck: {}",
+                    e
+                ));
+            }
+            Err(e) => {
+                self.connection.fail(
+                    attempt,
+                    FailureKind::Network,
+                    format!("Failed to fetch session info: {}", e),
+                );
+                return;
+            }
+        }
+        if !self
+            .connection
+            .advance(attempt, ConnectionPhase::FetchingState)
+        {
+            return;
+        }
+
+        let Some(client) = self.http_client() else {
+            self.connection
+                .fail(attempt, FailureKind::Network, "Invalid network settings.");
+            return;
+        };
+        let base_url = self.session_server_address.clone();
+        let session_id = self.session_id.clone();
+        let token = self.token.clone();
+        self.spawn_task(async move {
+            let result = get_session_state(&client, &base_url, &session_id, &token).await;
+            Internal::SessionStateFetched { attempt, result }
+        });
+    }
+
+    fn on_session_state_fetched(&mut self, attempt: u64, result: anyhow::Result<SessionState>) {
+        if !self.connection.is_current(attempt) {
+            return;
+        }
+        match result {
+            Ok(response) => {
+                self.current_slide_index = response.current_page as usize;
+                self.current_step = response.current_step as usize;
+                self.apply_vote_state(&response);
+            }
+            Err(e) if is_token_expired(&e) => {
+                self.connection
+                    .fail(attempt, FailureKind::Auth, "Session token expired.");
+                self.handle_token_expired();
+                return;
+            }
+            Err(e) => {
+                self.connection.fail(
+                    attempt,
+                    FailureKind::Network,
+                    format!("Failed to fetch session state: {}", e),
+                );
+                return;
+            }
+        }
+        if self
+            .connection
+            .advance(attempt, ConnectionPhase::ConnectingWs)
+        {
+            self.establish_ws_connection();
+        }
+    }
+
+    /// 失敗した接続をやり直す。トークンが使えなければOTPからになる
+    fn retry_connection(&mut self, otp: String) {
+        if self.session_id.is_empty() || self.reauth_required {
+            self.connect_to_session(self.profile.clone(), otp);
+        } else {
+            self.start_session_flow();
+        }
+    }
+
+    /// 試行番号を進め、進行中の結果をすべて捨てる。
+    /// 更新中だったトークンの結果も捨てるので、次の更新を待たせない
+    fn reset_connection(&mut self) {
+        self.connection.reset();
+        self.refreshing_token = false;
+    }
+
+    /// 進行中の接続を打ち切る。セッションのキャッシュは残すので再開はできる
+    fn cancel_connection(&mut self) {
+        self.reset_connection();
+        self.shutdown_transports();
+        self.status("Connection cancelled.");
+        self.resume_candidate = cache::load();
+    }
+
+    /// 手元のトークンで接続をやり直す。セッションがなければ何もしない
+    fn reconnect(&mut self) {
+        if self.session_id.is_empty() {
+            self.status("No session to reconnect.");
+            return;
+        }
+        self.reset_connection();
+        self.shutdown_transports();
+        self.metrics.record_reconnect();
+        self.log(
+            LogLevel::Info,
+            LogCategory::Network,
+            "接続を張り直します",
+            None,
+        );
+        self.start_session_flow();
+    }
+
+    fn pause_actuation(&mut self, paused: bool) {
+        self.actuation_paused.store(paused, Ordering::Relaxed);
+        let message = if paused {
+            "スライド操作を一時停止しました"
+        } else {
+            "スライド操作を再開しました"
+        };
+        self.log(LogLevel::Info, LogCategory::Actuator, message, None);
+    }
+
+    fn shutdown_transports(&mut self) {
+        if let Some(handle) = self.ws_handle.take() {
+            handle.shutdown(); // WebSocket切断実行
+        }
+        if let Some(poll_handle) = self.poll_handle.take() {
+            poll_handle.shutdown();
+        }
+        self.stop_vote_watch();
+        self.ws_connecting = false;
+    }
+
+    fn establish_ws_connection(&mut self) {
+        if self.ws_handle.is_some() {
+            return; // すでに接続済みなら何もしない
+        }
+
+        if self.ws_connecting {
+            return;
+        }
+        self.ws_connecting = true;
+
+        let network = self.profile.network.clone();
+        let base_url = self.session_server_address.clone();
+        let session_id = self.session_id.clone();
+        let token = self.token.clone();
+        let agent_name = self.profile.agent_name.clone();
+        let tracker = self.sequence_tracker.clone();
+        let controller = self.controller(CommandSource::Websocket);
+        let sender = self.event_tx.clone();
+        let metrics = self.metrics.clone();
+        let audit = self.audit.clone();
+        let attempt = self.connection.attempt();
+
+        self.spawn_task(async move {
+            let result = run_websocket(
+                &network,
+                WsTarget {
+                    base_url: &base_url,
+                    session_id: &session_id,
+                    token: &token,
+                    agent_name: &agent_name,
+                },
+                controller,
+                tracker,
+                sender,
+                metrics,
+                audit,
+            )
+            .await;
+            Internal::WsConnected { attempt, result }
+        });
+    }
+
+    fn on_ws_connected(&mut self, attempt: u64, result: anyhow::Result<WsHandle>) {
+        if !self.connection.is_current(attempt) {
+            // キャンセル・切断された試行の接続は使わない
+            if let Ok(ws_handle) = result {
+                ws_handle.shutdown();
+            }
+            return;
+        }
+        self.ws_connecting = false;
+        match result {
+            Ok(ws_handle) => {
+                self.ws_handle = Some(ws_handle);
+                // トークン更新で張り直したときはすでに`Registered`
+                if self.connection.phase() != &ConnectionPhase::Registered {
+                    self.connection
+                        .advance(attempt, ConnectionPhase::Registered);
+                }
+                self.status("WebSocket connection established.");
+                if let Some(poll_handle) = self.poll_handle.take() {
+                    poll_handle.shutdown();
+                    self.log(
+                        LogLevel::Info,
+                        LogCategory::Network,
+                        "WebSocketに復帰しました",
+                        None,
+                    );
+                }
+                self.start_vote_watch();
+            }
+            Err(e) => {
+                log::warn!("WebSocket error: {}", e);
+                self.log(
+                    LogLevel::Warn,
+                    LogCategory::Network,
+                    format!("WebSocket接続エラー: {}", e),
+                    None,
+                );
+                self.status(format!("WebSocket error: {}", e));
+                self.start_polling_fallback();
+            }
+        }
+    }
+
+    /// 一時停止と監査ログを挟んだ操作の実行先。`source`は監査ログに残す操作の出どころ
+    fn controller(&self, source: CommandSource) -> Arc<dyn Controller> {
+        let controller =
+            build_controller(self.profile.controller_backend, self.profile.key_profile);
+        let pausable = Arc::new(PausableController::new(
+            controller,
+            self.actuation_paused.clone(),
+        ));
+        Arc::new(AuditedController::new(
+            pausable,
+            self.audit.clone(),
+            self.actuation_paused.clone(),
+            self.session_id.clone(),
+            source,
+        ))
+    }
+
+    /// プロキシ・CA設定を反映したクライアント。設定が不正ならステータスに出して`None`
+    fn client_for(&self, network: &NetworkSettings) -> Option<reqwest::Client> {
+        match build_http_client(network) {
+            Ok(client) => Some(client),
+            Err(e) => {
+                self.status(format!("Invalid network settings: {}", e));
+                None
+            }
+        }
+    }
+
+    fn http_client(&self) -> Option<reqwest::Client> {
+        self.client_for(&self.profile.network)
+    }
+
+    /// WebSocketが張れない・切れたときはポーリングに切り替え、裏でWebSocketの再接続を試み続ける
+    fn start_polling_fallback(&mut self) {
+        if self.session_id.is_empty() {
+            return;
+        }
+        let attempt = self.connection.attempt();
+        match self.connection.phase() {
+            ConnectionPhase::ConnectingWs | ConnectionPhase::Registered => {
+                self.connection
+                    .advance(attempt, ConnectionPhase::Reconnecting);
+            }
+            ConnectionPhase::Reconnecting => {}
+            _ => return,
+        }
+
+        if self.poll_handle.is_none() {
+            let Some(target) = self.poll_target() else {
+                return;
+            };
+            // 全体のポーリングが投票も拾うので二重に取らない
+            self.stop_vote_watch();
+            let initial = Position {
+                page_index: self.current_slide_index,
+                step_index: self.current_step,
+            };
+            self.poll_handle = Some(run_polling(
+                target,
+                POLL_INTERVAL,
+                PollMode::Follow,
+                initial,
+                self.controller(CommandSource::Polling),
+                self.event_tx.clone(),
+            ));
+            self.status("WebSocket unavailable, polling session state.");
+            self.log(
+                LogLevel::Warn,
+                LogCategory::Network,
+                "WebSocketが使えないためポーリングに切り替えました",
+                None,
+            );
+        }
+
+        self.spawn_task(async move {
+            tokio::time::sleep(WS_RETRY_INTERVAL).await;
+            Internal::WsRetryDue { attempt }
+        });
+    }
+
+    fn poll_target(&self) -> Option<PollTarget> {
+        Some(PollTarget {
+            client: self.http_client()?,
+            base_url: self.session_server_address.clone(),
+            session_id: self.session_id.clone(),
+            token: self.token.clone(),
+        })
+    }
+
+    /// WebSocketは投票の集計を流さないので、接続中は状態を定期的に取って追う
+    fn start_vote_watch(&mut self) {
+        self.stop_vote_watch();
+        let Some(target) = self.poll_target() else {
+            return;
+        };
+        let initial = Position {
+            page_index: self.current_slide_index,
+            step_index: self.current_step,
+        };
+        self.vote_watch_handle = Some(run_polling(
+            target,
+            VOTE_POLL_INTERVAL,
+            PollMode::Observe,
+            initial,
+            self.controller(CommandSource::Polling),
+            self.event_tx.clone(),
+        ));
+    }
+
+    fn stop_vote_watch(&mut self) {
+        if let Some(handle) = self.vote_watch_handle.take() {
+            handle.shutdown();
+        }
+    }
+
+    fn apply_vote_state(&mut self, session_state: &SessionState) {
+        self.active_vote_ids = session_state.active_vote_ids.clone();
+        self.vote_tallies = session_state
+            .vote_summaries
+            .iter()
+            .map(|summary| (summary.vote_id.clone(), summary.choice_votes.clone()))
+            .collect();
+    }
+
+    fn pages(&self) -> &[SessionInfoPage] {
+        self.session_info
+            .as_ref()
+            .map(|info| info.pages.as_slice())
+            .unwrap_or_default()
+    }
+
+    /// 手動操作をサーバーに依頼する。結果はWebSocketのコマンドとして戻ってくる
+    fn navigate_upstream(&mut self, navigation: Navigation) {
+        let Some(ws_handle) = &self.ws_handle else {
+            self.status("Upstream navigation requires a WebSocket connection.");
+            return;
+        };
+        let request = match navigation {
+            Navigation::Next => WsRequest::TriggerNextStep,
+            Navigation::Prev => WsRequest::TriggerPrevStep,
+            Navigation::First => WsRequest::ChangeCurrentPage {
+                data: ChangeCurrentPageRequestData { new_page_index: 0 },
+            },
+            Navigation::Last => WsRequest::ChangeCurrentPage {
+                data: ChangeCurrentPageRequestData {
+                    new_page_index: self.pages().len().saturating_sub(1),
+                },
+            },
+            Navigation::GoTo(index) => WsRequest::ChangeCurrentPage {
+                data: ChangeCurrentPageRequestData {
+                    new_page_index: index,
+                },
+            },
+        };
+        if let Err(e) = ws_handle.send(&request) {
+            self.status(format!("Failed to send navigation request: {}", e));
+        }
+    }
+
+    /// この端末のスライドを直接操作する
+    fn navigate_locally(&mut self, navigation: Navigation) {
+        let current = Position {
+            page_index: self.current_slide_index,
+            step_index: self.current_step,
+        };
+        let pages = self.pages();
+        let last_page = pages.len().saturating_sub(1);
+        let steps_of = |page_index: usize| pages.get(page_index).map_or(0, |page| page.step);
+
+        let target = match navigation {
+            Navigation::Next if current.step_index < steps_of(current.page_index) => Position {
+                step_index: current.step_index + 1,
+                ..current
+            },
+            Navigation::Next if current.page_index < last_page => Position {
+                page_index: current.page_index + 1,
+                step_index: 0,
+            },
+            Navigation::Prev if current.step_index > 0 => Position {
+                step_index: current.step_index - 1,
+                ..current
+            },
+            Navigation::Prev if current.page_index > 0 => Position {
+                page_index: current.page_index - 1,
+                step_index: 0,
+            },
+            Navigation::Next | Navigation::Prev => current,
+            Navigation::First => Position {
+                page_index: 0,
+                step_index: 0,
+            },
+            Navigation::Last => Position {
+                page_index: last_page,
+                step_index: 0,
+            },
+            Navigation::GoTo(index) => Position {
+                page_index: index.min(last_page),
+                step_index: 0,
+            },
+        };
+
+        // 状態の更新はリモートからのコマンドと同じくイベント経由で行う
+        let (actions, events) = diff_positions(current, target);
+        let controller = self.controller(CommandSource::Manual);
+        let sender = self.event_tx.clone();
+        tokio::spawn(async move {
+            for action in actions {
+                execute(controller.clone(), action, &sender).await;
+            }
+        });
+        for event in events {
+            let _ = self.event_tx.send(event);
+        }
+    }
+
+    fn handle_connection_lost(&mut self) {
+        self.ws_handle = None;
+        self.start_polling_fallback();
+    }
+
+    /// トークンが変わったときに呼ぶ。再起動しても戻れるよう、書き終わるまで待つ
+    fn persist_session(&self) {
+        if let Some(cached) = self.cached_session() {
+            self.cache.save(&cached);
+        }
+    }
+
+    /// 位置やデッキが変わったときに呼ぶ。続けて変わる分をまとめ、裏で書く
+    fn schedule_persist(&mut self) {
+        if self.persist_scheduled {
+            return;
+        }
+        self.persist_scheduled = true;
+        self.spawn_task(async {
+            tokio::time::sleep(PERSIST_DELAY).await;
+            Internal::PersistDue
+        });
+    }
+
+    fn cached_session(&self) -> Option<CachedSession> {
+        if self.session_id.is_empty() {
+            return None;
+        }
+        Some(CachedSession {
+            primary_server_address: self.profile.primary_server_address.clone(),
+            session_server_address: self.session_server_address.clone(),
+            agent_name: self.profile.agent_name.clone(),
+            session_id: self.session_id.clone(),
+            token: self.token.clone(),
+            refresh_token: self.refresh_token.clone(),
+            token_expires_at: self.token_expires_at.map(cache::instant_to_unix),
+            session_info: self.session_info.clone(),
+            current_page_index: self.current_slide_index,
+            current_step: self.current_step,
+            saved_at: cache::now_unix(),
+        })
+    }
+
+    /// OTPを入れ直さずに前回のセッションへ戻る。デッキ情報はキャッシュから先に復元し、取得できれば上書きする
+    fn resume_last_session(&mut self, profile: ConnectionProfile) {
+        let Some(cached) = self.resume_candidate.take() else {
+            return;
+        };
+
+        self.profile = ConnectionProfile {
+            primary_server_address: cached.primary_server_address,
+            agent_name: cached.agent_name,
+            ..profile
+        };
+        self.session_server_address = cached.session_server_address;
+        self.session_id = cached.session_id;
+        self.token = cached.token;
+        self.refresh_token = cached.refresh_token;
+        self.token_expires_at = cached.token_expires_at.map(cache::unix_to_instant);
+        self.current_slide_index = cached.current_page_index;
+        self.current_step = cached.current_step;
+        if cached.session_info.is_some() {
+            self.session_info = cached.session_info;
+        }
+        self.status("Resuming last session...");
+        self.log(
+            LogLevel::Info,
+            LogCategory::Network,
+            "前回のセッションを再開しました",
+            None,
+        );
+
+        self.start_session_flow();
+    }
+
+    /// トークンを更新したあと、切断中に進んだ分を取り直す
+    fn fetch_session_state(&mut self) {
+        let Some(client) = self.http_client() else {
+            return;
+        };
+        let base_url = self.session_server_address.clone();
+        let session_id = self.session_id.clone();
+        let token = self.token.clone();
+        let attempt = self.connection.attempt();
+        self.spawn_task(async move {
+            let result = get_session_state(&client, &base_url, &session_id, &token).await;
+            Internal::StateRefreshed { attempt, result }
+        });
+    }
+
+    /// コマンドの取りこぼしを検知したとき、サーバーの状態に合わせてデッキを動かし直す
+    fn resync_session_state(&mut self) {
+        if self.resyncing {
+            return;
+        }
+        let Some(client) = self.http_client() else {
+            return;
+        };
+        let base_url = self.session_server_address.clone();
+        let session_id = self.session_id.clone();
+        let token = self.token.clone();
+        let attempt = self.connection.attempt();
+        self.resyncing = true;
+
+        self.spawn_task(async move {
+            let result = get_session_state(&client, &base_url, &session_id, &token).await;
+            Internal::Resynced { attempt, result }
+        });
+    }
+
+    fn on_resynced(&mut self, attempt: u64, result: anyhow::Result<SessionState>) {
+        self.resyncing = false;
+        if !self.connection.is_current(attempt) {
+            return;
+        }
+        match result {
+            Ok(response) => {
+                let current = Position {
+                    page_index: self.current_slide_index,
+                    step_index: self.current_step,
+                };
+                let next = Position::from(&response);
+                let (actions, events) = diff_positions(current, next);
+                let controller = self.controller(CommandSource::Resync);
+                let sender = self.event_tx.clone();
+                tokio::spawn(async move {
+                    for action in actions {
+                        execute(controller.clone(), action, &sender).await;
+                    }
+                });
+                // 手動操作と同じく、位置の更新はイベント経由で行う
+                for event in events {
+                    let _ = self.event_tx.send(event);
+                }
+                self.log(
+                    LogLevel::Info,
+                    LogCategory::Network,
+                    format!(
+                        "状態を再同期しました (ページ{}, ステップ{})",
+                        next.page_index, next.step_index
+                    ),
+                    Some(json!({
+                        "page_index": next.page_index,
+                        "step_index": next.step_index,
+                    })),
+                );
+            }
+            Err(e) if is_token_expired(&e) => {
+                self.handle_token_expired();
+            }
+            Err(e) => {
+                self.status(format!("Failed to resync session state: {}", e));
+            }
+        }
+    }
+
+    fn apply_token(
+        &mut self,
+        token: String,
+        expires_in: Option<u64>,
+        refresh_token: Option<String>,
+    ) {
+        self.token = token;
+        self.token_expires_at = expires_in.map(|secs| Instant::now() + Duration::from_secs(secs));
+        // リフレッシュトークンが返ってこなかった場合は前のものを使い続ける
+        if refresh_token.is_some() {
+            self.refresh_token = refresh_token;
+        }
+        self.persist_session();
+    }
+
+    /// 有効期限が近づいていたらリフレッシュを始める。始めたら`true`
+    fn check_token_expiry(&mut self) -> bool {
+        if self.refreshing_token || self.reauth_required {
+            return false;
+        }
+        match self.token_expires_at {
+            Some(expires_at) if Instant::now() + TOKEN_REFRESH_MARGIN >= expires_at => {
+                self.handle_token_expired();
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// リフレッシュトークンがあれば裏で更新し、なければOTPの再入力を求める。
+    /// スライドの状態(pages, current_slide_index)はそのまま残す
+    fn handle_token_expired(&mut self) {
+        if self.refreshing_token || self.reauth_required {
+            return;
+        }
+
+        let Some(current_refresh_token) = self.refresh_token.clone() else {
+            self.reauth_required = true;
+            self.log(
+                LogLevel::Warn,
+                LogCategory::Auth,
+                "トークンが失効しました。OTPの再入力が必要です",
+                None,
+            );
+            self.status("Token expired. Please enter a new OTP.");
+            return;
+        };
+
+        let Some(client) = self.http_client() else {
+            return;
+        };
+        self.refreshing_token = true;
+        self.status("Refreshing token...");
+
+        let base_url = self.profile.primary_server_address.clone();
+        let attempt = self.connection.attempt();
+        self.spawn_task(async move {
+            let result = refresh_token(&client, &base_url, &current_refresh_token).await;
+            Internal::TokenRefreshed { attempt, result }
+        });
+    }
+
+    fn on_token_refreshed(&mut self, attempt: u64, result: anyhow::Result<RefreshTokenResponse>) {
+        // 切断やキャンセルの後に返ってきた結果は、今のセッションのトークンではない
+        if !self.connection.is_current(attempt) {
+            return;
+        }
+        self.refreshing_token = false;
+        match result {
+            Ok(response) => {
+                self.apply_token(response.token, response.expires_in, response.refresh_token);
+                self.log(
+                    LogLevel::Info,
+                    LogCategory::Auth,
+                    "トークンを更新しました",
+                    None,
+                );
+                if self.connection.phase().is_connected() {
+                    self.status("Token refreshed.");
+                    self.reregister_ws();
+                } else {
+                    self.status("Token refreshed. Retry to continue.");
+                }
+            }
+            Err(e) => {
+                log::warn!("Token refresh failed: {}", e);
+                self.log(
+                    LogLevel::Error,
+                    LogCategory::Auth,
+                    format!("トークンの更新に失敗しました: {}", e),
+                    None,
+                );
+                self.refresh_token = None;
+                self.reauth_required = true;
+                self.status("Token expired. Please enter a new OTP.");
+            }
+        }
+    }
+
+    /// 新しいOTPで同じセッションに入り直す
+    fn reauthenticate(&mut self, otp: String) {
+        let Some(client) = self.http_client() else {
+            return;
+        };
+        let base_url = self.profile.primary_server_address.clone();
+        let attempt = self.connection.attempt();
+        self.status("Re-authenticating...");
+
+        self.spawn_task(async move {
+            let result = verify_otp(&client, &base_url, &otp).await;
+            Internal::Reauthenticated { attempt, result }
+        });
+    }
+
+    fn on_reauthenticated(&mut self, attempt: u64, result: anyhow::Result<VerifyOtpResponse>) {
+        if !self.connection.is_current(attempt) {
+            return;
+        }
+        match result {
+            Ok(response) if response.session_id != self.session_id => {
+                self.status("The OTP belongs to a different session.");
+            }
+            Ok(response) => {
+                self.session_server_address = response.aggregator_url;
+                self.apply_token(response.token, response.expires_in, response.refresh_token);
+                self.reauth_required = false;
+                self.log(LogLevel::Info, LogCategory::Auth, "再認証しました", None);
+                self.status("Re-authenticated.");
+                self.reregister_ws();
+            }
+            Err(e) => {
+                self.log(
+                    LogLevel::Error,
+                    LogCategory::Auth,
+                    format!("OTPの検証に失敗しました: {}", e),
+                    None,
+                );
+                self.status(format!("OTP Verification Failed: {}", e));
+            }
+        }
+    }
+
+    /// 新しいトークンでWebSocketを張り直し、切断中に進んだ分を取り直す
+    fn reregister_ws(&mut self) {
+        if let Some(handle) = self.ws_handle.take() {
+            handle.shutdown();
+        }
+        // ポーリング中なら古いトークンのまま回り続けないよう作り直す
+        if let Some(poll_handle) = self.poll_handle.take() {
+            poll_handle.shutdown();
+            self.start_polling_fallback();
+        }
+        self.establish_ws_connection();
+        self.fetch_session_state();
+    }
+
+    fn disconnect(&mut self) {
+        self.reset_connection();
+        self.shutdown_transports();
+        self.timer.reset();
+        self.active_vote_ids.clear();
+        self.vote_tallies.clear();
+        // 次に別のセッションへ入ったとき、前のデッキと位置を引き継がない
+        self.session_info = None;
+        self.current_slide_index = 0;
+        self.current_step = 0;
+        self.reauth_required = false;
+        self.token_expires_at = None;
+        self.refresh_token = None;
+        self.status("Disconnected");
+        self.sequence_tracker = Arc::default();
+        let _ = self.event_broadcast.send(Event::Disconnected);
+        // 明示的に切断したセッションは再開候補にしない
+        self.session_id.clear();
+        self.cache.clear();
+    }
+}
@@ -1,8 +1,42 @@
-use crate::models::auth::{VerifyOtpRequest, VerifyOtpResponse};
+use crate::models::auth::{
+    RefreshTokenRequest, RefreshTokenResponse, VerifyOtpRequest, VerifyOtpResponse,
+};
 use log::{debug, error, info};
 use reqwest::Client;
 use anyhow::Result;
 
+/// サーバーが401を返したときのエラー。呼び出し側は`downcast_ref`で判別して再認証する
+#[derive(Debug)]
+pub struct TokenExpired;
+
+impl std::fmt::Display for TokenExpired {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Token expired or rejected by server")
+    }
+}
+
+impl std::error::Error for TokenExpired {}
+
+pub fn is_token_expired(error: &anyhow::Error) -> bool {
+    error.downcast_ref::<TokenExpired>().is_some()
+}
+
+/// OTPの検証でサーバーが401か403を返したときのエラー。それ以外の失敗はサーバー側の問題として扱う
+#[derive(Debug)]
+pub struct OtpRejected;
+
+impl std::fmt::Display for OtpRejected {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "OTP rejected by server")
+    }
+}
+
+impl std::error::Error for OtpRejected {}
+
+pub fn is_otp_rejected(error: &anyhow::Error) -> bool {
+    error.downcast_ref::<OtpRejected>().is_some()
+}
+
 pub async fn verify_otp(
     client: &Client,
     base_url: &str,
@@ -19,8 +53,38 @@ pub async fn verify_otp(
         let response = resp.json::<VerifyOtpResponse>().await?;
         info!("OTP verified successfully");
         Ok(response)
+    } else if matches!(
+        resp.status(),
+        reqwest::StatusCode::UNAUTHORIZED | reqwest::StatusCode::FORBIDDEN
+    ) {
+        error!("OTP rejected");
+        Err(OtpRejected.into())
     } else {
         error!("OTP verification failed");
-        Err(anyhow::anyhow!("OTP verification failed"))
+        Err(anyhow::anyhow!("OTP verification failed: HTTP {}", resp.status()))
+    }
+}
+
+pub async fn refresh_token(
+    client: &Client,
+    base_url: &str,
+    refresh_token: &str,
+) -> Result<RefreshTokenResponse, anyhow::Error> {
+    let url = format!("{}/session/agent/refresh", base_url);
+    let request = RefreshTokenRequest {
+        refresh_token: refresh_token.to_string(),
+    };
+    let resp = client.post(&url).json(&request).send().await?;
+
+    if resp.status().is_success() {
+        let response = resp.json::<RefreshTokenResponse>().await?;
+        info!("Token refreshed successfully");
+        Ok(response)
+    } else if resp.status() == reqwest::StatusCode::UNAUTHORIZED {
+        error!("Refresh token rejected");
+        Err(TokenExpired.into())
+    } else {
+        error!("Token refresh failed");
+        Err(anyhow::anyhow!("Token refresh failed"))
     }
 }
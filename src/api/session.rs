@@ -1,3 +1,4 @@
+use crate::api::auth::TokenExpired;
 use crate::models::session::SessionInfo;
 use log::{error, info};
 use reqwest::Client;
@@ -19,6 +20,9 @@ pub async fn get_session_info(
         let response = resp.json::<SessionInfo>().await?;
         info!("Session info received successfully");
         Ok(response)
+    } else if resp.status() == reqwest::StatusCode::UNAUTHORIZED {
+        error!("Token rejected while fetching session info");
+        Err(TokenExpired.into())
     } else {
         error!("Failed to get session info");
         Err(anyhow::anyhow!("Failed to get session info"))
@@ -1,3 +1,4 @@
+use crate::api::auth::TokenExpired;
 use crate::models::state::SessionState;
 use anyhow::Result;
 use log::{error, info};
@@ -19,6 +20,9 @@ pub async fn get_session_state(
         let response = resp.json::<SessionState>().await?;
         info!("Session state received successfully");
         Ok(response)
+    } else if resp.status() == reqwest::StatusCode::UNAUTHORIZED {
+        error!("Token rejected while fetching session state");
+        Err(TokenExpired.into())
     } else {
         error!("Failed to get session state");
         Err(anyhow::anyhow!("Failed to get session state"))
@@ -0,0 +1,252 @@
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{mpsc, Arc};
+
+use anyhow::{Context, Result};
+use chrono::{DateTime, Local};
+use serde::Serialize;
+
+use crate::config::AuditConfig;
+use crate::controller::{Action, Controller};
+use crate::paths::data_dir;
+
+/// コマンドがどこから来たか
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum CommandSource {
+    Websocket,
+    /// WebSocketが使えない間、ポーリングで追いかけた分
+    Polling,
+    /// コマンドの抜けを埋めるために状態を取り直した分
+    Resync,
+    /// GUIや制御ソケットからの手動操作
+    Manual,
+}
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Outcome {
+    Executed,
+    Failed,
+    /// 操作の一時停止中で、スライドには触れなかった
+    Skipped,
+    /// 重複・古い・抜けのあるコマンドとして実行しなかった
+    Dropped,
+}
+
+/// 監査ログの1行
+#[derive(Serialize, Debug)]
+struct AuditRecord<'a> {
+    timestamp: DateTime<Local>,
+    session_id: &'a str,
+    source: CommandSource,
+    action: Action,
+    outcome: Outcome,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    detail: Option<&'a str>,
+}
+
+/// リモートからの操作を1行1件のJSONで追記していく。`env_logger`の出力とは別のファイルに書き、
+/// 一定の大きさを超えたら`audit.log.1`、`audit.log.2`…と世代を送る。
+/// 書き込みと同期は専用のスレッドで行い、記録する側を待たせない
+pub struct AuditLog {
+    lines: Option<mpsc::Sender<Vec<u8>>>,
+}
+
+struct AuditFile {
+    path: PathBuf,
+    file: File,
+    size: u64,
+    max_bytes: u64,
+    max_files: usize,
+}
+
+pub fn default_path() -> Option<PathBuf> {
+    data_dir().map(|dir| dir.join("audit.log"))
+}
+
+impl AuditLog {
+    pub fn open(config: &AuditConfig) -> Result<Self> {
+        if !config.enabled {
+            return Ok(AuditLog::disabled());
+        }
+        let path = config
+            .path
+            .clone()
+            .or_else(default_path)
+            .context("No data directory available for the audit log")?;
+        let file = open_append(&path)?;
+        let size = file.metadata()?.len();
+        log::info!("Writing audit log to {}", path.display());
+        let mut file = AuditFile {
+            path,
+            file,
+            size,
+            max_bytes: config.max_bytes,
+            max_files: config.max_files,
+        };
+        let (lines_tx, lines_rx) = mpsc::channel::<Vec<u8>>();
+        // 送り手がすべてなくなったら、残りを書き切って終わる
+        std::thread::Builder::new()
+            .name("audit-log".to_owned())
+            .spawn(move || {
+                for line in lines_rx {
+                    if let Err(e) = file.append(&line) {
+                        log::error!("Failed to write audit log: {:#}", e);
+                    }
+                }
+            })
+            .context("Failed to start the audit log writer")?;
+        Ok(AuditLog {
+            lines: Some(lines_tx),
+        })
+    }
+
+    pub fn disabled() -> Self {
+        AuditLog { lines: None }
+    }
+
+    pub fn record(
+        &self,
+        session_id: &str,
+        source: CommandSource,
+        action: Action,
+        outcome: Outcome,
+        detail: Option<&str>,
+    ) {
+        let Some(lines) = &self.lines else {
+            return;
+        };
+        let record = AuditRecord {
+            timestamp: Local::now(),
+            session_id,
+            source,
+            action,
+            outcome,
+            detail,
+        };
+        let mut line = match serde_json::to_vec(&record) {
+            Ok(line) => line,
+            Err(e) => {
+                log::error!("Failed to write audit log: {}", e);
+                return;
+            }
+        };
+        line.push(b'\n');
+        if lines.send(line).is_err() {
+            log::error!("Failed to write audit log: writer stopped");
+        }
+    }
+}
+
+impl AuditFile {
+    fn append(&mut self, line: &[u8]) -> Result<()> {
+        if self.size > 0 && self.size + line.len() as u64 > self.max_bytes {
+            self.rotate()?;
+        }
+        self.file.write_all(line)?;
+        // 落ちても直前の操作までは残るよう、1件ごとにディスクへ書き出す
+        self.file.sync_data()?;
+        self.size += line.len() as u64;
+        Ok(())
+    }
+
+    fn rotate(&mut self) -> Result<()> {
+        let rotated = |index: usize| {
+            let mut name = self.path.clone().into_os_string();
+            name.push(format!(".{}", index));
+            PathBuf::from(name)
+        };
+        let _ = std::fs::remove_file(rotated(self.max_files));
+        for index in (1..self.max_files).rev() {
+            let from = rotated(index);
+            if from.exists() {
+                std::fs::rename(&from, rotated(index + 1))?;
+            }
+        }
+        std::fs::rename(&self.path, rotated(1))?;
+        self.file = open_append(&self.path)?;
+        self.size = 0;
+        Ok(())
+    }
+}
+
+fn open_append(path: &Path) -> Result<File> {
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    let mut options = OpenOptions::new();
+    options.append(true).create(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    options
+        .open(path)
+        .with_context(|| format!("Failed to open audit log {}", path.display()))
+}
+
+/// 実行した操作とその結果を監査ログに残す
+pub struct AuditedController {
+    inner: Arc<dyn Controller>,
+    audit: Arc<AuditLog>,
+    /// 一時停止中の操作を`Skipped`として残すために見る
+    paused: Arc<AtomicBool>,
+    session_id: String,
+    source: CommandSource,
+}
+
+impl AuditedController {
+    pub fn new(
+        inner: Arc<dyn Controller>,
+        audit: Arc<AuditLog>,
+        paused: Arc<AtomicBool>,
+        session_id: String,
+        source: CommandSource,
+    ) -> Self {
+        AuditedController {
+            inner,
+            audit,
+            paused,
+            session_id,
+            source,
+        }
+    }
+
+    fn audited(&self, action: Action, run: impl FnOnce() -> Result<()>) -> Result<()> {
+        let paused = self.paused.load(Ordering::Relaxed);
+        let result = run();
+        let (outcome, detail) = match &result {
+            Ok(()) if paused => (Outcome::Skipped, None),
+            Ok(()) => (Outcome::Executed, None),
+            Err(e) => (Outcome::Failed, Some(e.to_string())),
+        };
+        self.audit.record(
+            &self.session_id,
+            self.source,
+            action,
+            outcome,
+            detail.as_deref(),
+        );
+        result
+    }
+}
+
+impl Controller for AuditedController {
+    fn change_page(&self, new_page_index: usize) -> Result<()> {
+        self.audited(Action::ChangePage(new_page_index), || {
+            self.inner.change_page(new_page_index)
+        })
+    }
+
+    fn next_step(&self) -> Result<()> {
+        self.audited(Action::NextStep, || self.inner.next_step())
+    }
+
+    fn prev_step(&self) -> Result<()> {
+        self.audited(Action::PrevStep, || self.inner.prev_step())
+    }
+}
@@ -0,0 +1,119 @@
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use anyhow::{anyhow, Result};
+use log::{debug, warn};
+use serde::{Deserialize, Serialize};
+
+use crate::models::session::SessionInfo;
+use crate::paths::{data_dir, write_private};
+
+/// 再起動後にOTPなしで同じセッションへ戻るための情報。トークンを含むので所有者だけが読めるように保存する
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct CachedSession {
+    pub primary_server_address: String,
+    pub session_server_address: String,
+    pub agent_name: String,
+    pub session_id: String,
+    pub token: String,
+    pub refresh_token: Option<String>,
+    /// UNIX時刻(秒)
+    pub token_expires_at: Option<u64>,
+    pub session_info: Option<SessionInfo>,
+    pub current_page_index: usize,
+    pub current_step: usize,
+    pub saved_at: u64,
+}
+
+fn cache_path() -> Option<PathBuf> {
+    data_dir().map(|dir| dir.join("last_session.json"))
+}
+
+pub fn load() -> Option<CachedSession> {
+    let path = cache_path()?;
+    let contents = std::fs::read(&path).ok()?;
+    match serde_json::from_slice(&contents) {
+        Ok(cached) => Some(cached),
+        Err(e) => {
+            warn!("Ignoring broken session cache {}: {}", path.display(), e);
+            None
+        }
+    }
+}
+
+pub fn save(cached: &CachedSession) -> Result<()> {
+    let path = cache_path().ok_or_else(|| anyhow!("No data directory available"))?;
+    write_private(&path, &serde_json::to_vec_pretty(cached)?)?;
+    debug!("Session cache saved to {}", path.display());
+    Ok(())
+}
+
+/// 書き込みの順番を守る窓口。裏で書いている古い内容が、後から書いた内容や削除を上書きしないようにする
+#[derive(Clone, Default)]
+pub struct CacheWriter {
+    /// 最後に反映した書き込みの番号
+    written: Arc<Mutex<u64>>,
+    issued: Arc<AtomicU64>,
+}
+
+impl CacheWriter {
+    /// 書き終わるまで待つ
+    pub fn save(&self, cached: &CachedSession) {
+        self.write(self.ticket(), Some(cached));
+    }
+
+    /// 同期とファイルの置き換えを呼び出し元で待たない
+    pub fn save_in_background(&self, cached: CachedSession) {
+        let writer = self.clone();
+        let ticket = self.ticket();
+        tokio::task::spawn_blocking(move || writer.write(ticket, Some(&cached)));
+    }
+
+    pub fn clear(&self) {
+        self.write(self.ticket(), None);
+    }
+
+    fn ticket(&self) -> u64 {
+        self.issued.fetch_add(1, Ordering::Relaxed) + 1
+    }
+
+    fn write(&self, ticket: u64, cached: Option<&CachedSession>) {
+        let mut written = self.written.lock().unwrap();
+        if *written > ticket {
+            debug!("Skipping outdated session cache write");
+            return;
+        }
+        match cached {
+            Some(cached) => {
+                if let Err(e) = save(cached) {
+                    warn!("Failed to save session cache: {}", e);
+                }
+            }
+            None => clear(),
+        }
+        *written = ticket;
+    }
+}
+
+pub fn clear() {
+    if let Some(path) = cache_path() {
+        let _ = std::fs::remove_file(path);
+    }
+}
+
+pub fn now_unix() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+pub fn instant_to_unix(instant: Instant) -> u64 {
+    now_unix() + instant.saturating_duration_since(Instant::now()).as_secs()
+}
+
+pub fn unix_to_instant(unix: u64) -> Instant {
+    Instant::now() + Duration::from_secs(unix.saturating_sub(now_unix()))
+}
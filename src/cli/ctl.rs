@@ -0,0 +1,102 @@
+use std::path::PathBuf;
+use std::process::ExitCode;
+
+use clap::{Args, Subcommand};
+use serde_json::{json, Value};
+
+use desktop_agent::control::{socket_path, ControlClient};
+
+#[derive(Args, Debug)]
+pub struct CtlArgs {
+    /// Control socket of the running agent
+    #[arg(long)]
+    pub socket: Option<PathBuf>,
+    #[command(subcommand)]
+    pub command: CtlCommand,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum CtlCommand {
+    /// Show connection state and position
+    Status,
+    /// Show the current page and step
+    Position,
+    /// Re-establish the connection with the current token
+    Reconnect,
+    /// Leave the session
+    Disconnect,
+    /// Keep following the session without touching the slides
+    Pause,
+    /// Drive the slides again after a pause
+    Resume,
+    /// Go to the next step
+    Next {
+        /// Ask the server instead of driving this machine
+        #[arg(long)]
+        upstream: bool,
+    },
+    /// Go to the previous step
+    Prev {
+        /// Ask the server instead of driving this machine
+        #[arg(long)]
+        upstream: bool,
+    },
+    /// Jump to a page (0-based)
+    Goto {
+        page: usize,
+        /// Ask the server instead of driving this machine
+        #[arg(long)]
+        upstream: bool,
+    },
+    /// Print events as JSON lines until the agent exits
+    Subscribe,
+}
+
+impl CtlCommand {
+    fn request(&self) -> (&'static str, Value) {
+        match self {
+            CtlCommand::Status => ("status", Value::Null),
+            CtlCommand::Position => ("position", Value::Null),
+            CtlCommand::Reconnect => ("reconnect", Value::Null),
+            CtlCommand::Disconnect => ("disconnect", Value::Null),
+            CtlCommand::Pause => ("pause", Value::Null),
+            CtlCommand::Resume => ("resume", Value::Null),
+            CtlCommand::Next { upstream } => ("next", json!({ "upstream": upstream })),
+            CtlCommand::Prev { upstream } => ("prev", json!({ "upstream": upstream })),
+            CtlCommand::Goto { page, upstream } => {
+                ("goto", json!({ "page": page, "upstream": upstream }))
+            }
+            CtlCommand::Subscribe => ("subscribe", Value::Null),
+        }
+    }
+}
+
+/// 動いているエージェントに1つ操作を送り、結果をJSONで出す
+pub fn run(args: CtlArgs) -> ExitCode {
+    match call(args) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("error: {:#}", e);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+fn call(args: CtlArgs) -> anyhow::Result<()> {
+    let path = args
+        .socket
+        .or_else(socket_path)
+        .ok_or_else(|| anyhow::anyhow!("cannot determine the control socket path"))?;
+    let mut client = ControlClient::connect(&path)?;
+    let (method, params) = args.command.request();
+    let result = client.call(method, params)?;
+
+    if matches!(args.command, CtlCommand::Subscribe) {
+        while let Some(event) = client.next_event()? {
+            println!("{}", event);
+        }
+    } else {
+        println!("{}", serde_json::to_string_pretty(&result)?);
+    }
+    Ok(())
+}
@@ -0,0 +1,331 @@
+use std::io::Write;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::process::ExitCode;
+
+use clap::{Args, Parser, Subcommand};
+use log::{error, info, Level};
+
+use desktop_agent::agent::{self, AgentHandle, Command, Notification};
+use desktop_agent::audit::AuditLog;
+use desktop_agent::config::{self, AgentConfig, ConfigOverrides, LoggingConfig};
+use desktop_agent::connection::{ConnectionPhase, FailureKind};
+use desktop_agent::controller::{ControllerBackend, KeyProfile};
+use desktop_agent::eventlog::LogLevel;
+use desktop_agent::settings::ConnectionProfile;
+use desktop_agent::{hooks, mqtt, obs, osc, status, webhooks};
+
+#[cfg(unix)]
+pub mod ctl;
+
+/// 設定が読めない、または足りない。clapの引数の誤りと同じ番号にする
+const EXIT_CONFIG_INVALID: u8 = 2;
+/// OTPやトークンが拒否された。再起動しても直らないので人の手が要る
+const EXIT_AUTH_FAILED: u8 = 3;
+/// サーバーに届かない。時間を置いて再起動すれば直ることがある
+const EXIT_CONNECTION_FAILED: u8 = 4;
+
+#[derive(Parser, Debug)]
+#[command(name = "desktop-agent", version, about = "PresenStudio desktop agent")]
+pub struct Cli {
+    /// Config file (default: config.toml in the user config directory)
+    #[arg(long, global = true)]
+    pub config: Option<PathBuf>,
+    /// Log level: off, error, warn, info, debug or trace
+    #[arg(long, global = true)]
+    pub log_level: Option<String>,
+    // 省略したらGUIで起動する
+    #[command(subcommand)]
+    pub command: Option<CliCommand>,
+}
+
+impl Cli {
+    /// サブコマンドによらない上書き。環境変数より優先する
+    pub fn overrides(&self) -> ConfigOverrides {
+        ConfigOverrides {
+            path: self.config.clone(),
+            log_level: self.log_level.clone(),
+            ..ConfigOverrides::default()
+        }
+    }
+}
+
+#[derive(Subcommand, Debug)]
+pub enum CliCommand {
+    /// Connect and drive the slides without a window
+    Run(RunArgs),
+    /// Query or control a running agent through its control socket
+    #[cfg(unix)]
+    Ctl(ctl::CtlArgs),
+}
+
+#[derive(Args, Debug)]
+pub struct RunArgs {
+    /// Primary server URL
+    #[arg(long)]
+    pub server: Option<String>,
+    /// One-time password shown by the presenter
+    #[arg(long)]
+    pub otp: Option<String>,
+    /// Agent name shown to the presenter
+    #[arg(long)]
+    pub name: Option<String>,
+    #[arg(long, value_enum)]
+    pub backend: Option<ControllerBackend>,
+    #[arg(long, value_enum)]
+    pub keys: Option<KeyProfile>,
+    /// Resume the cached session instead of verifying an OTP, if one exists
+    #[arg(long)]
+    pub resume: bool,
+    /// HTTP CONNECT or SOCKS5 proxy URL
+    #[arg(long)]
+    pub proxy: Option<String>,
+    /// Extra CA certificate (PEM); may be given more than once
+    #[arg(long = "ca-cert")]
+    pub ca_certs: Vec<String>,
+    /// Serve /status and /metrics on this address (overrides the config file)
+    #[arg(long)]
+    pub status_listen: Option<SocketAddr>,
+}
+
+impl RunArgs {
+    fn overrides(&self) -> ConfigOverrides {
+        ConfigOverrides {
+            server: self.server.clone(),
+            agent_name: self.name.clone(),
+            backend: self.backend,
+            key_profile: self.keys,
+            proxy: self.proxy.clone(),
+            ca_certs: self.ca_certs.clone(),
+            status_listen: self.status_listen,
+            ..ConfigOverrides::default()
+        }
+    }
+}
+
+/// journaldが優先度として読む`<N>`を頭に付け、時刻は付けない
+fn init_logger(logging: &LoggingConfig) {
+    let mut builder = env_logger::Builder::from_default_env();
+    builder
+        .target(env_logger::Target::Stdout)
+        .format(|buf, record| {
+            let priority = match record.level() {
+                Level::Error => 3,
+                Level::Warn => 4,
+                Level::Info => 6,
+                Level::Debug | Level::Trace => 7,
+            };
+            writeln!(buf, "<{}>{}: {}", priority, record.target(), record.args())
+        });
+    logging.init_logger(builder);
+}
+
+/// ウィンドウを出さずに接続し、止められるか失敗するまで動き続ける
+pub fn run(args: RunArgs, overrides: ConfigOverrides) -> ExitCode {
+    let overrides = ConfigOverrides {
+        path: overrides.path,
+        log_level: overrides.log_level,
+        ..args.overrides()
+    };
+    let loaded = config::load(&overrides);
+    // 読めなかったときも、その旨は既定のレベルで出す
+    let logging = loaded
+        .as_ref()
+        .map(|config| config.logging.clone())
+        .unwrap_or_default();
+    init_logger(&logging);
+    let config = match loaded {
+        Ok(config) => config,
+        Err(e) => {
+            error!("{:#}", e);
+            return ExitCode::from(EXIT_CONFIG_INVALID);
+        }
+    };
+    if let Some(path) = &config.source {
+        info!("Loaded config from {}", path.display());
+    }
+
+    let runtime = match tokio::runtime::Runtime::new() {
+        Ok(runtime) => runtime,
+        Err(e) => {
+            error!("Failed to start Tokio runtime: {}", e);
+            return ExitCode::FAILURE;
+        }
+    };
+    runtime.block_on(run_headless(args, config, overrides))
+}
+
+async fn run_headless(args: RunArgs, config: AgentConfig, overrides: ConfigOverrides) -> ExitCode {
+    let mut profile = ConnectionProfile::default();
+    config.connection.apply_to(&mut profile);
+    if profile.primary_server_address.is_empty() || profile.agent_name.is_empty() {
+        error!(
+            "Server and agent name are required: set connection.server and connection.agent_name \
+             in the config, DESKTOP_AGENT_SERVER and DESKTOP_AGENT_NAME, or --server and --name"
+        );
+        return ExitCode::from(EXIT_CONFIG_INVALID);
+    }
+
+    // 監査ログを残せないならキー操作は始めない
+    let audit = match AuditLog::open(&config.audit) {
+        Ok(audit) => audit,
+        Err(e) => {
+            error!("{:#}", e);
+            return ExitCode::FAILURE;
+        }
+    };
+
+    let runtime = tokio::runtime::Handle::current();
+    let mut agent = agent::spawn(&runtime, audit, || {});
+    #[cfg(unix)]
+    desktop_agent::control::spawn(&runtime, agent.client());
+    if config.status_server.enabled {
+        status::spawn(&runtime, config.status_server.listen, agent.client());
+    }
+    let hooks = hooks::spawn(&runtime, agent.client(), config.hooks.clone());
+    let webhooks = webhooks::spawn(
+        &runtime,
+        agent.client(),
+        config.webhooks.clone(),
+        &config.connection.network(),
+        || {},
+    );
+    let obs = config
+        .obs
+        .enabled
+        .then(|| obs::spawn(&runtime, agent.client(), config.obs.clone()));
+    let osc = config
+        .osc
+        .enabled
+        .then(|| osc::spawn(&runtime, agent.client(), config.osc.clone()));
+    if config.mqtt.enabled {
+        if let Err(e) = mqtt::spawn(&runtime, agent.client(), &config.mqtt) {
+            error!("{:#}", e);
+            return ExitCode::FAILURE;
+        }
+    }
+    // 接続の設定は起動時のものを使い続けるので、反映するのはログの出力レベルと連携のルールだけ
+    config::watch(&runtime, overrides, move |result| {
+        if let Ok(config) = result {
+            hooks.update(config.hooks);
+            webhooks.update(config.webhooks);
+            if let Some(obs) = &obs {
+                obs.update(config.obs.rules);
+            }
+            if let Some(osc) = &osc {
+                osc.update(config.osc.messages);
+            }
+        }
+    });
+
+    if args.resume && agent.snapshot().resume_candidate.is_some() {
+        info!("Resuming cached session");
+        agent.send(Command::ResumeLastSession { profile });
+    } else if let Some(otp) = args.otp.clone() {
+        agent.send(Command::Connect { profile, otp });
+    } else {
+        error!("No cached session to resume; --otp is required");
+        return ExitCode::from(EXIT_CONFIG_INVALID);
+    }
+
+    let mut snapshots = agent.subscribe();
+    let shutdown = shutdown_signal();
+    tokio::pin!(shutdown);
+    let mut last_phase = ConnectionPhase::Idle;
+
+    loop {
+        tokio::select! {
+            Some(notification) = agent.recv() => log_notification(notification),
+            changed = snapshots.changed() => {
+                if changed.is_err() {
+                    error!("Agent core stopped unexpectedly");
+                    return ExitCode::FAILURE;
+                }
+                let snapshot = snapshots.borrow_and_update().clone();
+                if snapshot.phase != last_phase {
+                    info!("Connection: {}", snapshot.phase.label());
+                    last_phase = snapshot.phase.clone();
+                }
+                if let Some(code) = exit_code(&snapshot.phase, snapshot.reauth_required) {
+                    drain_notifications(&mut agent);
+                    return code;
+                }
+            }
+            _ = &mut shutdown => {
+                // キャッシュは残し、次の起動で`--resume`できるようにする
+                info!("Shutting down");
+                return ExitCode::SUCCESS;
+            }
+        }
+    }
+}
+
+/// 人の操作なしには先へ進めない状態なら終了コードを返す
+fn exit_code(phase: &ConnectionPhase, reauth_required: bool) -> Option<ExitCode> {
+    match phase {
+        ConnectionPhase::Failed { kind, reason } => {
+            error!("Connection failed: {}", reason);
+            Some(ExitCode::from(match kind {
+                FailureKind::Auth => EXIT_AUTH_FAILED,
+                FailureKind::Network => EXIT_CONNECTION_FAILED,
+            }))
+        }
+        _ if reauth_required => {
+            error!("Session token expired and no OTP can be entered");
+            Some(ExitCode::from(EXIT_AUTH_FAILED))
+        }
+        _ => None,
+    }
+}
+
+/// 終了前に、失敗の経緯を出し切る
+fn drain_notifications(agent: &mut AgentHandle) {
+    while let Some(notification) = agent.try_recv() {
+        log_notification(notification);
+    }
+}
+
+fn log_notification(notification: Notification) {
+    match notification {
+        Notification::Status(message) => info!(target: "status", "{}", message),
+        Notification::Log(entry) => {
+            let level = match entry.level {
+                LogLevel::Info => Level::Info,
+                LogLevel::Warn => Level::Warn,
+                LogLevel::Error => Level::Error,
+            };
+            match &entry.payload {
+                Some(payload) => log::log!(
+                    target: entry.category.label(),
+                    level,
+                    "{} {}",
+                    entry.message,
+                    payload
+                ),
+                None => log::log!(target: entry.category.label(), level, "{}", entry.message),
+            }
+        }
+    }
+}
+
+async fn shutdown_signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        match signal(SignalKind::terminate()) {
+            Ok(mut terminate) => {
+                tokio::select! {
+                    _ = tokio::signal::ctrl_c() => {}
+                    _ = terminate.recv() => {}
+                }
+            }
+            Err(_) => {
+                let _ = tokio::signal::ctrl_c().await;
+            }
+        }
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = tokio::signal::ctrl_c().await;
+    }
+}
@@ -0,0 +1,839 @@
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::{Duration, SystemTime};
+
+use anyhow::{anyhow, bail, Context, Result};
+use clap::ValueEnum;
+use log::{error, info, LevelFilter};
+use serde::Deserialize;
+
+use crate::controller::{ControllerBackend, KeyProfile};
+use crate::eventlog::LogCategory;
+use crate::models::events::Event;
+use crate::net::NetworkSettings;
+use crate::paths::config_dir;
+use crate::settings::{
+    ConnectionProfile, DisplaySettings, ThemeChoice, MAX_FONT_SCALE, MIN_FONT_SCALE,
+};
+
+/// 設定ファイルの変更を確かめる間隔
+const RELOAD_INTERVAL: Duration = Duration::from_secs(2);
+
+/// `config.toml`の内容。ファイルがなければすべて既定値。
+/// 既定値 < ファイル < 環境変数 < コマンドライン引数 の順に上書きする
+#[derive(Deserialize, Debug, Default, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct AgentConfig {
+    pub connection: ConnectionConfig,
+    pub logging: LoggingConfig,
+    pub ui: UiConfig,
+    pub pacing: PacingConfig,
+    pub status_server: StatusServerConfig,
+    pub audit: AuditConfig,
+    pub hooks: Vec<HookConfig>,
+    pub webhooks: Vec<WebhookConfig>,
+    pub obs: ObsConfig,
+    pub osc: OscConfig,
+    pub mqtt: MqttConfig,
+    /// 読み込んだファイル。なければ`None`
+    #[serde(skip)]
+    pub source: Option<PathBuf>,
+}
+
+/// 接続フォームの初期値。起動時にだけ使い、再読み込みでは反映しない
+#[derive(Deserialize, Debug, Default, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct ConnectionConfig {
+    pub server: Option<String>,
+    pub agent_name: Option<String>,
+    pub backend: Option<ControllerBackend>,
+    pub key_profile: Option<KeyProfile>,
+    pub proxy: Option<String>,
+    pub ca_certs: Vec<String>,
+}
+
+impl ConnectionConfig {
+    /// 設定ファイルに書かれたプロキシと証明書。プロファイルを介さない連携機能が使う
+    pub fn network(&self) -> NetworkSettings {
+        NetworkSettings {
+            proxy_url: self.proxy.clone().unwrap_or_default(),
+            ca_cert_paths: self.ca_certs.join("\n"),
+        }
+    }
+
+    /// 設定されている項目だけプロファイルに上書きする
+    pub fn apply_to(&self, profile: &mut ConnectionProfile) {
+        if let Some(server) = &self.server {
+            profile.primary_server_address = server.clone();
+        }
+        if let Some(agent_name) = &self.agent_name {
+            profile.agent_name = agent_name.clone();
+        }
+        if let Some(backend) = self.backend {
+            profile.controller_backend = backend;
+        }
+        if let Some(key_profile) = self.key_profile {
+            profile.key_profile = key_profile;
+        }
+        if let Some(proxy) = &self.proxy {
+            profile.network.proxy_url = proxy.clone();
+        }
+        if !self.ca_certs.is_empty() {
+            profile.network.ca_cert_paths = self.ca_certs.join("\n");
+        }
+    }
+}
+
+/// `RUST_LOG`がないときに出すログのターゲット。`status`はヘッドレス実行の状態表示。
+/// ヘッドレス実行は操作ログをカテゴリ名(`network`など)のターゲットで出すので、それも含める
+fn filter_own_targets(builder: &mut env_logger::Builder) {
+    let categories = LogCategory::ALL.iter().map(LogCategory::label);
+    for target in ["desktop_agent", "status"].into_iter().chain(categories) {
+        builder.filter_module(target, LevelFilter::Trace);
+    }
+}
+
+fn rust_log_set() -> bool {
+    std::env::var_os("RUST_LOG").is_some_and(|filters| !filters.is_empty())
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct LoggingConfig {
+    /// off / error / warn / info / debug / trace
+    pub level: String,
+}
+
+impl Default for LoggingConfig {
+    fn default() -> Self {
+        LoggingConfig {
+            level: "info".to_owned(),
+        }
+    }
+}
+
+impl LoggingConfig {
+    pub fn level_filter(&self) -> Result<LevelFilter> {
+        LevelFilter::from_str(&self.level).map_err(|_| {
+            anyhow!(
+                "unknown level {:?} (expected off, error, warn, info, debug or trace)",
+                self.level
+            )
+        })
+    }
+
+    /// 出力先などを済ませた`builder`でロガーを始め、設定のレベルに合わせる。
+    /// `RUST_LOG`がなければこのクレートのログだけを出す
+    pub fn init_logger(&self, mut builder: env_logger::Builder) {
+        if !rust_log_set() {
+            // 再読み込みでレベルを上げられるよう、ここでは絞らず`apply`で抑える
+            filter_own_targets(&mut builder);
+        }
+        builder.init();
+        self.apply();
+    }
+
+    /// ログの出力レベルを切り替える。`RUST_LOG`があるときはそちらに任せて何もしない
+    pub fn apply(&self) {
+        if rust_log_set() {
+            return;
+        }
+        if let Ok(level) = self.level_filter() {
+            log::set_max_level(level);
+        }
+    }
+}
+
+/// GUIの表示。設定されている項目はGUIで選んだものより優先する
+#[derive(Deserialize, Debug, Default, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct UiConfig {
+    pub theme: Option<ThemeChoice>,
+    pub font_scale: Option<f32>,
+}
+
+impl UiConfig {
+    /// 変わったら`true`
+    pub fn apply_to(&self, display: &mut DisplaySettings) -> bool {
+        let before = display.clone();
+        if let Some(theme) = self.theme {
+            display.theme = theme;
+        }
+        if let Some(font_scale) = self.font_scale {
+            display.font_scale = font_scale;
+        }
+        *display != before
+    }
+}
+
+/// 発表全体とページごとの目標時間(秒)
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct PacingConfig {
+    pub total_seconds: Option<u64>,
+    /// 目標時間のこの割合を過ぎたら黄色で警告する
+    pub warning_ratio: f32,
+    /// page_id -> 秒
+    pub pages: HashMap<String, u64>,
+}
+
+impl Default for PacingConfig {
+    fn default() -> Self {
+        PacingConfig {
+            total_seconds: None,
+            warning_ratio: 0.8,
+            pages: HashMap::new(),
+        }
+    }
+}
+
+/// 監視用のHTTPサーバー。既定では開かない。変更は再起動後に反映する
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct StatusServerConfig {
+    pub enabled: bool,
+    pub listen: SocketAddr,
+}
+
+impl Default for StatusServerConfig {
+    fn default() -> Self {
+        StatusServerConfig {
+            enabled: false,
+            listen: SocketAddr::from(([127, 0, 0, 1], 9464)),
+        }
+    }
+}
+
+/// リモートからの操作の監査ログ。変更は再起動後に反映する
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct AuditConfig {
+    pub enabled: bool,
+    /// 省略したらデータ用の場所の`audit.log`
+    pub path: Option<PathBuf>,
+    /// これを超えたら世代を送る
+    pub max_bytes: u64,
+    /// 残しておく古い世代の数
+    pub max_files: usize,
+}
+
+impl Default for AuditConfig {
+    fn default() -> Self {
+        AuditConfig {
+            enabled: true,
+            path: None,
+            max_bytes: 10 * 1024 * 1024,
+            max_files: 5,
+        }
+    }
+}
+
+/// イベントが起きたときに実行するコマンド。`[[hooks]]`として並べる
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct HookConfig {
+    /// `slide_changed`などのイベント名
+    pub events: Vec<String>,
+    /// シェルを通さずに実行する
+    pub command: String,
+    #[serde(default)]
+    pub args: Vec<String>,
+    /// これを過ぎても終わらなければ止める
+    #[serde(default = "default_hook_timeout")]
+    pub timeout_secs: u64,
+}
+
+fn default_hook_timeout() -> u64 {
+    10
+}
+
+/// イベントをJSONでPOSTする先。`[[webhooks]]`として並べる
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct WebhookConfig {
+    pub url: String,
+    /// `slide_changed`などのイベント名
+    pub events: Vec<String>,
+    /// 指定したら本文にHMAC-SHA256の署名を付ける
+    pub secret: Option<String>,
+    /// 最初の1回を含む送信回数の上限
+    #[serde(default = "default_webhook_attempts")]
+    pub max_attempts: u32,
+    /// 1回の送信で応答を待つ時間
+    #[serde(default = "default_hook_timeout")]
+    pub timeout_secs: u64,
+}
+
+fn default_webhook_attempts() -> u32 {
+    5
+}
+
+/// obs-websocket v5でOBS Studioを操作する。接続先の変更は再起動後、ルールの変更はすぐに反映する
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct ObsConfig {
+    pub enabled: bool,
+    pub url: String,
+    pub password: Option<String>,
+    pub rules: Vec<ObsRule>,
+}
+
+impl Default for ObsConfig {
+    fn default() -> Self {
+        ObsConfig {
+            enabled: false,
+            url: "ws://127.0.0.1:4455".to_owned(),
+            password: None,
+            rules: Vec::new(),
+        }
+    }
+}
+
+/// `page_index`・`page_id`・`event`のどれか1つが合ったら、指定された操作をすべて行う
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct ObsRule {
+    pub page_index: Option<usize>,
+    pub page_id: Option<String>,
+    /// `vote_started`などのイベント名
+    pub event: Option<String>,
+    /// 切り替える先のシーン
+    pub scene: Option<String>,
+    pub toggle_source: Option<ObsSourceToggle>,
+    /// 録画に付けるチャプターの名前
+    pub chapter: Option<String>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct ObsSourceToggle {
+    pub scene: String,
+    pub source: String,
+    pub enabled: bool,
+}
+
+/// OSC(UDP)での照明・音響卓との連携。送る内容の変更はすぐに、それ以外は再起動後に反映する
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct OscConfig {
+    pub enabled: bool,
+    /// 送り先。省略したら送らない
+    pub target: Option<SocketAddr>,
+    /// 受け付けるアドレス。省略したら受けない
+    pub listen: Option<SocketAddr>,
+    /// 受けた前後の操作をサーバーに依頼するか、この端末のスライドを直接操作するか
+    pub upstream: bool,
+    pub next_address: String,
+    pub prev_address: String,
+    pub messages: Vec<OscMessageConfig>,
+}
+
+impl Default for OscConfig {
+    fn default() -> Self {
+        let message = |event: &str, address: &str, args: &[&str]| OscMessageConfig {
+            event: event.to_owned(),
+            address: address.to_owned(),
+            args: args.iter().map(|arg| arg.to_string()).collect(),
+        };
+        OscConfig {
+            enabled: false,
+            target: None,
+            listen: None,
+            upstream: false,
+            next_address: "/presen/next".to_owned(),
+            prev_address: "/presen/prev".to_owned(),
+            messages: vec![
+                message("slide_changed", "/presen/page", &["{new_page_index}"]),
+                message(
+                    "step_changed",
+                    "/presen/step",
+                    &["{new_page_index}", "{new_step_index}"],
+                ),
+                message("vote_started", "/presen/vote/started", &["{vote_id}"]),
+                message("vote_closed", "/presen/vote/closed", &["{vote_id}"]),
+            ],
+        }
+    }
+}
+
+/// イベントが起きたときに送るOSCメッセージ
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct OscMessageConfig {
+    /// `slide_changed`などのイベント名
+    pub event: String,
+    pub address: String,
+    /// `{new_page_index}`のようにイベントの項目を埋め込める。整数・小数に読めるものは数値で送る
+    #[serde(default)]
+    pub args: Vec<String>,
+}
+
+/// MQTTブローカーへの状態の公開と、操作の受け付け。変更は再起動後に反映する
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct MqttConfig {
+    pub enabled: bool,
+    /// `mqtt://host:1883`または`mqtts://host:8883`
+    pub url: String,
+    /// 省略したら`desktop-agent-<プロセスID>`
+    pub client_id: Option<String>,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    /// `mqtts`でブローカーの証明書を確かめるPEM。省略したらOSの証明書を使う
+    pub ca_cert: Option<PathBuf>,
+    /// 公開・購読するトピックの頭
+    pub topic_prefix: String,
+    /// 受けた操作をサーバーに依頼するか、この端末のスライドを直接操作するか
+    pub upstream: bool,
+}
+
+impl Default for MqttConfig {
+    fn default() -> Self {
+        MqttConfig {
+            enabled: false,
+            url: "mqtt://127.0.0.1:1883".to_owned(),
+            client_id: None,
+            username: None,
+            password: None,
+            ca_cert: None,
+            topic_prefix: "presen".to_owned(),
+            upstream: false,
+        }
+    }
+}
+
+/// 環境変数やコマンドライン引数で指定された値。指定のない項目は`None`
+#[derive(Debug, Default, Clone)]
+pub struct ConfigOverrides {
+    pub path: Option<PathBuf>,
+    pub server: Option<String>,
+    pub agent_name: Option<String>,
+    pub backend: Option<ControllerBackend>,
+    pub key_profile: Option<KeyProfile>,
+    pub proxy: Option<String>,
+    pub ca_certs: Vec<String>,
+    pub log_level: Option<String>,
+    pub status_listen: Option<SocketAddr>,
+}
+
+impl ConfigOverrides {
+    /// `DESKTOP_AGENT_*`の環境変数から読む
+    fn from_env() -> Result<Self> {
+        let var = |key: &str| std::env::var(key).ok().filter(|value| !value.is_empty());
+        Ok(ConfigOverrides {
+            path: var("DESKTOP_AGENT_CONFIG").map(PathBuf::from),
+            server: var("DESKTOP_AGENT_SERVER"),
+            agent_name: var("DESKTOP_AGENT_NAME"),
+            backend: var("DESKTOP_AGENT_BACKEND")
+                .map(|value| parse_value_enum("DESKTOP_AGENT_BACKEND", &value))
+                .transpose()?,
+            key_profile: var("DESKTOP_AGENT_KEY_PROFILE")
+                .map(|value| parse_value_enum("DESKTOP_AGENT_KEY_PROFILE", &value))
+                .transpose()?,
+            proxy: var("DESKTOP_AGENT_PROXY"),
+            ca_certs: var("DESKTOP_AGENT_CA_CERTS")
+                .map(|value| {
+                    std::env::split_paths(&value)
+                        .map(|path| path.display().to_string())
+                        .collect()
+                })
+                .unwrap_or_default(),
+            log_level: var("DESKTOP_AGENT_LOG_LEVEL"),
+            status_listen: var("DESKTOP_AGENT_STATUS_LISTEN")
+                .map(|value| {
+                    value.parse().with_context(|| {
+                        format!("DESKTOP_AGENT_STATUS_LISTEN: invalid address {:?}", value)
+                    })
+                })
+                .transpose()?,
+        })
+    }
+
+    /// `other`で指定された項目で上書きする
+    fn merge(mut self, other: ConfigOverrides) -> Self {
+        self.path = other.path.or(self.path);
+        self.server = other.server.or(self.server);
+        self.agent_name = other.agent_name.or(self.agent_name);
+        self.backend = other.backend.or(self.backend);
+        self.key_profile = other.key_profile.or(self.key_profile);
+        self.proxy = other.proxy.or(self.proxy);
+        if !other.ca_certs.is_empty() {
+            self.ca_certs = other.ca_certs;
+        }
+        self.log_level = other.log_level.or(self.log_level);
+        self.status_listen = other.status_listen.or(self.status_listen);
+        self
+    }
+
+    fn apply_to(&self, config: &mut AgentConfig) {
+        let connection = &mut config.connection;
+        if let Some(server) = &self.server {
+            connection.server = Some(server.clone());
+        }
+        if let Some(agent_name) = &self.agent_name {
+            connection.agent_name = Some(agent_name.clone());
+        }
+        if let Some(backend) = self.backend {
+            connection.backend = Some(backend);
+        }
+        if let Some(key_profile) = self.key_profile {
+            connection.key_profile = Some(key_profile);
+        }
+        if let Some(proxy) = &self.proxy {
+            connection.proxy = Some(proxy.clone());
+        }
+        if !self.ca_certs.is_empty() {
+            connection.ca_certs = self.ca_certs.clone();
+        }
+        if let Some(level) = &self.log_level {
+            config.logging.level = level.clone();
+        }
+        if let Some(listen) = self.status_listen {
+            config.status_server.enabled = true;
+            config.status_server.listen = listen;
+        }
+    }
+}
+
+fn parse_value_enum<T: ValueEnum>(key: &str, value: &str) -> Result<T> {
+    // ファイルと同じ`page_up_down`の形でも、引数と同じ`page-up-down`の形でも受け付ける
+    T::from_str(&value.replace('_', "-"), true).map_err(|_| {
+        let expected: Vec<String> = T::value_variants()
+            .iter()
+            .filter_map(|variant| variant.to_possible_value())
+            .map(|possible| possible.get_name().to_owned())
+            .collect();
+        anyhow!(
+            "{}: unknown value {:?} (expected {})",
+            key,
+            value,
+            expected.join(", ")
+        )
+    })
+}
+
+pub fn config_path() -> Option<PathBuf> {
+    config_dir().map(|dir| dir.join("config.toml"))
+}
+
+/// 環境変数の上にコマンドライン引数を重ねる
+fn layered(cli: &ConfigOverrides) -> Result<ConfigOverrides> {
+    Ok(ConfigOverrides::from_env()?.merge(cli.clone()))
+}
+
+/// ファイル・環境変数・コマンドライン引数の`cli`を重ねて読み、まとめて検証する。
+/// 既定の場所にファイルがないのは構わないが、明示されたファイルがなければエラー
+pub fn load(cli: &ConfigOverrides) -> Result<AgentConfig> {
+    let overrides = layered(cli)?;
+    let explicit = overrides.path.is_some();
+    let path = overrides.path.clone().or_else(config_path);
+
+    let mut config = match &path {
+        Some(path) if explicit || path.exists() => {
+            let mut config = read_file(path)?;
+            config.source = Some(path.clone());
+            config
+        }
+        _ => AgentConfig::default(),
+    };
+    overrides.apply_to(&mut config);
+    config.validate()?;
+    Ok(config)
+}
+
+fn read_file(path: &Path) -> Result<AgentConfig> {
+    let contents = std::fs::read_to_string(path)
+        .with_context(|| format!("Failed to read config {}", path.display()))?;
+    toml::from_str(&contents).with_context(|| format!("Invalid config {}", path.display()))
+}
+
+impl AgentConfig {
+    /// おかしな値をすべて集めて1つのエラーにする
+    fn validate(&self) -> Result<()> {
+        let mut problems = Vec::new();
+
+        if let Some(server) = &self.connection.server {
+            if !(server.starts_with("http://") || server.starts_with("https://")) {
+                problems.push(format!(
+                    "connection.server: {:?} must start with http:// or https://",
+                    server
+                ));
+            }
+        }
+        if self
+            .connection
+            .agent_name
+            .as_ref()
+            .is_some_and(|name| name.trim().is_empty())
+        {
+            problems.push("connection.agent_name: must not be empty".to_owned());
+        }
+        if let Some(proxy) = &self.connection.proxy {
+            let network = NetworkSettings {
+                proxy_url: proxy.clone(),
+                ..NetworkSettings::default()
+            };
+            if let Err(e) = network.proxy() {
+                problems.push(format!("connection.proxy: {}", e));
+            }
+        }
+        for ca_cert in &self.connection.ca_certs {
+            if !Path::new(ca_cert).is_file() {
+                problems.push(format!("connection.ca_certs: {} does not exist", ca_cert));
+            }
+        }
+        if let Err(e) = self.logging.level_filter() {
+            problems.push(format!("logging.level: {}", e));
+        }
+        if let Some(font_scale) = self.ui.font_scale {
+            if !(MIN_FONT_SCALE..=MAX_FONT_SCALE).contains(&font_scale) {
+                problems.push(format!(
+                    "ui.font_scale: {} is outside {}..={}",
+                    font_scale, MIN_FONT_SCALE, MAX_FONT_SCALE
+                ));
+            }
+        }
+        if !(self.pacing.warning_ratio > 0.0 && self.pacing.warning_ratio <= 1.0) {
+            problems.push(format!(
+                "pacing.warning_ratio: {} must be greater than 0 and at most 1",
+                self.pacing.warning_ratio
+            ));
+        }
+        if self.pacing.total_seconds == Some(0) {
+            problems.push("pacing.total_seconds: must be greater than 0".to_owned());
+        }
+        if self.audit.max_bytes == 0 {
+            problems.push("audit.max_bytes: must be greater than 0".to_owned());
+        }
+        if self.audit.max_files == 0 {
+            problems.push("audit.max_files: must be at least 1".to_owned());
+        }
+        for (index, hook) in self.hooks.iter().enumerate() {
+            if hook.events.is_empty() {
+                problems.push(format!("hooks[{}].events: must not be empty", index));
+            }
+            for event in &hook.events {
+                if !Event::NAMES.contains(&event.as_str()) {
+                    problems.push(format!(
+                        "hooks[{}].events: unknown event {:?} (expected one of {})",
+                        index,
+                        event,
+                        Event::NAMES.join(", ")
+                    ));
+                }
+            }
+            if hook.command.trim().is_empty() {
+                problems.push(format!("hooks[{}].command: must not be empty", index));
+            }
+            if hook.timeout_secs == 0 {
+                problems.push(format!(
+                    "hooks[{}].timeout_secs: must be greater than 0",
+                    index
+                ));
+            }
+        }
+        for (index, webhook) in self.webhooks.iter().enumerate() {
+            match reqwest::Url::parse(&webhook.url) {
+                Ok(url) if matches!(url.scheme(), "http" | "https") => {}
+                Ok(url) => problems.push(format!(
+                    "webhooks[{}].url: unsupported scheme {}",
+                    index,
+                    url.scheme()
+                )),
+                Err(e) => problems.push(format!("webhooks[{}].url: {}", index, e)),
+            }
+            if webhook.events.is_empty() {
+                problems.push(format!("webhooks[{}].events: must not be empty", index));
+            }
+            for event in &webhook.events {
+                if !Event::NAMES.contains(&event.as_str()) {
+                    problems.push(format!(
+                        "webhooks[{}].events: unknown event {:?} (expected one of {})",
+                        index,
+                        event,
+                        Event::NAMES.join(", ")
+                    ));
+                }
+            }
+            if webhook.max_attempts == 0 {
+                problems.push(format!(
+                    "webhooks[{}].max_attempts: must be at least 1",
+                    index
+                ));
+            }
+            if webhook.timeout_secs == 0 {
+                problems.push(format!(
+                    "webhooks[{}].timeout_secs: must be greater than 0",
+                    index
+                ));
+            }
+        }
+        match reqwest::Url::parse(&self.obs.url) {
+            Ok(url) if matches!(url.scheme(), "ws" | "wss") => {}
+            Ok(url) => problems.push(format!("obs.url: unsupported scheme {}", url.scheme())),
+            Err(e) => problems.push(format!("obs.url: {}", e)),
+        }
+        for (index, rule) in self.obs.rules.iter().enumerate() {
+            let triggers = [
+                rule.page_index.is_some(),
+                rule.page_id.is_some(),
+                rule.event.is_some(),
+            ];
+            if triggers.iter().filter(|set| **set).count() != 1 {
+                problems.push(format!(
+                    "obs.rules[{}]: set exactly one of page_index, page_id or event",
+                    index
+                ));
+            }
+            if let Some(event) = &rule.event {
+                if !Event::NAMES.contains(&event.as_str()) {
+                    problems.push(format!(
+                        "obs.rules[{}].event: unknown event {:?} (expected one of {})",
+                        index,
+                        event,
+                        Event::NAMES.join(", ")
+                    ));
+                }
+            }
+            if rule.scene.is_none() && rule.toggle_source.is_none() && rule.chapter.is_none() {
+                problems.push(format!(
+                    "obs.rules[{}]: set at least one of scene, toggle_source or chapter",
+                    index
+                ));
+            }
+        }
+        for (name, address) in [
+            ("next_address", &self.osc.next_address),
+            ("prev_address", &self.osc.prev_address),
+        ] {
+            if !address.starts_with('/') {
+                problems.push(format!("osc.{}: must start with /", name));
+            }
+        }
+        for (index, message) in self.osc.messages.iter().enumerate() {
+            if !Event::NAMES.contains(&message.event.as_str()) {
+                problems.push(format!(
+                    "osc.messages[{}].event: unknown event {:?} (expected one of {})",
+                    index,
+                    message.event,
+                    Event::NAMES.join(", ")
+                ));
+            }
+            if !message.address.starts_with('/') {
+                problems.push(format!(
+                    "osc.messages[{}].address: must start with /",
+                    index
+                ));
+            }
+        }
+        match reqwest::Url::parse(&self.mqtt.url) {
+            Ok(url) if !matches!(url.scheme(), "mqtt" | "mqtts") => {
+                problems.push(format!("mqtt.url: unsupported scheme {}", url.scheme()))
+            }
+            Ok(url) if url.host_str().is_none() => {
+                problems.push("mqtt.url: missing host".to_owned())
+            }
+            Ok(_) => {}
+            Err(e) => problems.push(format!("mqtt.url: {}", e)),
+        }
+        if let Some(ca_cert) = &self.mqtt.ca_cert {
+            if !ca_cert.is_file() {
+                problems.push(format!("mqtt.ca_cert: {} does not exist", ca_cert.display()));
+            }
+        }
+        if self.mqtt.password.is_some() && self.mqtt.username.is_none() {
+            problems.push("mqtt.password: requires mqtt.username".to_owned());
+        }
+        let prefix = &self.mqtt.topic_prefix;
+        if prefix.is_empty() || prefix.ends_with('/') || prefix.contains(['+', '#']) {
+            problems.push(format!(
+                "mqtt.topic_prefix: {:?} must be non-empty, without wildcards or a trailing /",
+                prefix
+            ));
+        }
+
+        if problems.is_empty() {
+            return Ok(());
+        }
+        let source = self
+            .source
+            .as_ref()
+            .map(|path| path.display().to_string())
+            .unwrap_or_else(|| "configuration".to_owned());
+        bail!("Invalid {}:\n  - {}", source, problems.join("\n  - "))
+    }
+}
+
+/// 設定ファイルが変わったら読み直して`on_reload`に渡す。読めなかったときはエラーを渡すので、
+/// 呼び出し側は前の設定のまま使い続ける。ログの出力レベルはここで切り替える
+pub fn watch(
+    runtime: &tokio::runtime::Handle,
+    cli: ConfigOverrides,
+    on_reload: impl Fn(Result<AgentConfig>) + Send + 'static,
+) {
+    let path = layered(&cli)
+        .ok()
+        .and_then(|overrides| overrides.path)
+        .or_else(config_path);
+    let Some(path) = path else {
+        return;
+    };
+    runtime.spawn(async move {
+        let mut last_modified = modified(&path);
+        let mut interval = tokio::time::interval(RELOAD_INTERVAL);
+        loop {
+            interval.tick().await;
+            let current = modified(&path);
+            if current == last_modified {
+                continue;
+            }
+            last_modified = current;
+            let result = load(&cli);
+            match &result {
+                Ok(config) => {
+                    info!("Reloaded config from {}", path.display());
+                    config.logging.apply();
+                }
+                Err(e) => error!("Keeping previous config: {:#}", e),
+            }
+            on_reload(result);
+        }
+    });
+}
+
+fn modified(path: &Path) -> Option<SystemTime> {
+    std::fs::metadata(path)
+        .and_then(|metadata| metadata.modified())
+        .ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use log::{Level, Log, Metadata};
+
+    fn enabled(logger: &env_logger::Logger, target: &str) -> bool {
+        logger.enabled(
+            &Metadata::builder()
+                .target(target)
+                .level(Level::Info)
+                .build(),
+        )
+    }
+
+    #[test]
+    fn default_filter_covers_own_targets_only() {
+        let mut builder = env_logger::Builder::new();
+        filter_own_targets(&mut builder);
+        let logger = builder.build();
+
+        assert!(enabled(&logger, "desktop_agent::agent"));
+        assert!(enabled(&logger, "status"));
+        assert!(enabled(&logger, "network"));
+        assert!(enabled(&logger, "auth"));
+        assert!(!enabled(&logger, "reqwest::connect"));
+        assert!(!enabled(&logger, "tungstenite"));
+    }
+}
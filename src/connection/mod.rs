@@ -0,0 +1,225 @@
+/// 接続の段階。GUIの表示とボタンの出し分けはこれだけを見る
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub enum ConnectionPhase {
+    #[default]
+    Idle,
+    VerifyingOtp,
+    FetchingInfo,
+    FetchingState,
+    ConnectingWs,
+    Registered,
+    /// WebSocketが使えず、ポーリングで追いながら張り直しを待っている
+    Reconnecting,
+    Failed { kind: FailureKind, reason: String },
+}
+
+/// 失敗の原因。ヘッドレス実行では終了コードの出し分けに使う
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FailureKind {
+    /// OTPやトークンが受け付けられなかった
+    Auth,
+    /// サーバーに届かない、応答がおかしいなど
+    Network,
+}
+
+impl ConnectionPhase {
+    pub fn label(&self) -> &'static str {
+        match self {
+            ConnectionPhase::Idle => "Not Connected",
+            ConnectionPhase::VerifyingOtp => "Verifying OTP",
+            ConnectionPhase::FetchingInfo => "Fetching session info",
+            ConnectionPhase::FetchingState => "Fetching session state",
+            ConnectionPhase::ConnectingWs => "Connecting WebSocket",
+            ConnectionPhase::Registered => "Connected",
+            ConnectionPhase::Reconnecting => "Connected (polling)",
+            ConnectionPhase::Failed { .. } => "Failed",
+        }
+    }
+
+    /// 接続手順の進み具合。手順の途中でなければ`None`
+    pub fn progress(&self) -> Option<f32> {
+        match self {
+            ConnectionPhase::VerifyingOtp => Some(0.1),
+            ConnectionPhase::FetchingInfo => Some(0.35),
+            ConnectionPhase::FetchingState => Some(0.6),
+            ConnectionPhase::ConnectingWs => Some(0.85),
+            _ => None,
+        }
+    }
+
+    pub fn is_connected(&self) -> bool {
+        matches!(
+            self,
+            ConnectionPhase::Registered | ConnectionPhase::Reconnecting
+        )
+    }
+
+    fn can_transition_to(&self, next: &ConnectionPhase) -> bool {
+        use ConnectionPhase::*;
+        match (self, next) {
+            // キャンセルと切断はどこからでも
+            (_, Idle) => true,
+            (Idle, _) => false,
+            (_, Failed { .. }) => true,
+            (VerifyingOtp, FetchingInfo) => true,
+            (FetchingInfo, FetchingState) => true,
+            (FetchingState, ConnectingWs) => true,
+            (ConnectingWs, Registered | Reconnecting) => true,
+            (Registered, Reconnecting) => true,
+            (Reconnecting, Registered) => true,
+            _ => false,
+        }
+    }
+}
+
+/// 段階と試行番号。キャンセル後に返ってきた古い試行の結果は番号で見分けて捨てる
+#[derive(Debug, Default)]
+pub struct ConnectionState {
+    phase: ConnectionPhase,
+    attempt: u64,
+}
+
+impl ConnectionState {
+    pub fn phase(&self) -> &ConnectionPhase {
+        &self.phase
+    }
+
+    pub fn attempt(&self) -> u64 {
+        self.attempt
+    }
+
+    pub fn is_current(&self, attempt: u64) -> bool {
+        self.attempt == attempt
+    }
+
+    /// 新しい試行を始める。`Idle`か`Failed`から、OTP検証かデッキ情報の取得で始める
+    pub fn begin(&mut self, first: ConnectionPhase) -> Option<u64> {
+        let startable = matches!(
+            self.phase,
+            ConnectionPhase::Idle | ConnectionPhase::Failed { .. }
+        ) && matches!(
+            first,
+            ConnectionPhase::VerifyingOtp | ConnectionPhase::FetchingInfo
+        );
+        if !startable {
+            return None;
+        }
+        self.attempt += 1;
+        self.phase = first;
+        Some(self.attempt)
+    }
+
+    /// 試行が生きていて遷移が正しければ進める。進めなかったら`false`
+    pub fn advance(&mut self, attempt: u64, next: ConnectionPhase) -> bool {
+        if !self.is_current(attempt) {
+            return false;
+        }
+        if !self.phase.can_transition_to(&next) {
+            log::warn!(
+                "Ignoring connection transition {:?} -> {:?}",
+                self.phase,
+                next
+            );
+            return false;
+        }
+        self.phase = next;
+        true
+    }
+
+    pub fn fail(&mut self, attempt: u64, kind: FailureKind, reason: impl Into<String>) -> bool {
+        self.advance(
+            attempt,
+            ConnectionPhase::Failed {
+                kind,
+                reason: reason.into(),
+            },
+        )
+    }
+
+    /// 進行中の試行を打ち切る。以降に返ってくる結果は`is_current`で弾かれる
+    pub fn reset(&mut self) {
+        self.attempt += 1;
+        self.phase = ConnectionPhase::Idle;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn registered() -> (ConnectionState, u64) {
+        let mut state = ConnectionState::default();
+        let attempt = state.begin(ConnectionPhase::VerifyingOtp).unwrap();
+        for next in [
+            ConnectionPhase::FetchingInfo,
+            ConnectionPhase::FetchingState,
+            ConnectionPhase::ConnectingWs,
+            ConnectionPhase::Registered,
+        ] {
+            assert!(state.advance(attempt, next));
+        }
+        (state, attempt)
+    }
+
+    #[test]
+    fn begin_only_from_idle_or_failed() {
+        let mut state = ConnectionState::default();
+        let attempt = state.begin(ConnectionPhase::VerifyingOtp).unwrap();
+        assert_eq!(state.begin(ConnectionPhase::VerifyingOtp), None);
+
+        assert!(state.fail(attempt, FailureKind::Network, "down"));
+        assert!(state.begin(ConnectionPhase::FetchingInfo).is_some());
+
+        let (mut state, _) = registered();
+        assert_eq!(state.begin(ConnectionPhase::FetchingInfo), None);
+        assert_eq!(state.phase(), &ConnectionPhase::Registered);
+    }
+
+    #[test]
+    fn begin_rejects_other_first_phases() {
+        let mut state = ConnectionState::default();
+        assert_eq!(state.begin(ConnectionPhase::Registered), None);
+        assert_eq!(state.phase(), &ConnectionPhase::Idle);
+    }
+
+    #[test]
+    fn advance_rejects_stale_attempt() {
+        let mut state = ConnectionState::default();
+        let first = state.begin(ConnectionPhase::VerifyingOtp).unwrap();
+        assert!(state.fail(first, FailureKind::Auth, "bad otp"));
+        let second = state.begin(ConnectionPhase::VerifyingOtp).unwrap();
+
+        assert!(!state.advance(first, ConnectionPhase::FetchingInfo));
+        assert!(!state.fail(first, FailureKind::Network, "late"));
+        assert_eq!(state.phase(), &ConnectionPhase::VerifyingOtp);
+        assert!(state.advance(second, ConnectionPhase::FetchingInfo));
+    }
+
+    #[test]
+    fn illegal_jumps_are_refused() {
+        let mut state = ConnectionState::default();
+        let attempt = state.begin(ConnectionPhase::FetchingInfo).unwrap();
+        assert!(!state.advance(attempt, ConnectionPhase::Registered));
+        assert!(!state.advance(attempt, ConnectionPhase::ConnectingWs));
+        assert_eq!(state.phase(), &ConnectionPhase::FetchingInfo);
+    }
+
+    #[test]
+    fn reset_invalidates_in_flight_attempt() {
+        let mut state = ConnectionState::default();
+        let attempt = state.begin(ConnectionPhase::VerifyingOtp).unwrap();
+        state.reset();
+        assert!(!state.is_current(attempt));
+        assert!(!state.advance(attempt, ConnectionPhase::FetchingInfo));
+        assert_eq!(state.phase(), &ConnectionPhase::Idle);
+    }
+
+    #[test]
+    fn registered_and_reconnecting_switch_both_ways() {
+        let (mut state, attempt) = registered();
+        assert!(state.advance(attempt, ConnectionPhase::Reconnecting));
+        assert!(state.phase().is_connected());
+        assert!(state.advance(attempt, ConnectionPhase::Registered));
+        assert!(state.phase().is_connected());
+    }
+}
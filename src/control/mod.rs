@@ -0,0 +1,363 @@
+use std::io::{BufRead, BufReader as StdBufReader, Write};
+use std::os::unix::net::UnixStream as StdUnixStream;
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, bail, Context, Result};
+use log::{error, info, warn};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader, Lines};
+use tokio::net::unix::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::net::{UnixListener, UnixStream};
+use tokio::sync::broadcast;
+
+use crate::agent::{AgentClient, Command, Snapshot};
+use crate::controller::Navigation;
+use crate::models::events::Event;
+use crate::paths::runtime_dir;
+
+const PARSE_ERROR: i64 = -32700;
+const METHOD_NOT_FOUND: i64 = -32601;
+const INVALID_PARAMS: i64 = -32602;
+/// 中核が止まっていて操作を受け付けられない
+const AGENT_STOPPED: i64 = -32000;
+
+pub fn socket_path() -> Option<PathBuf> {
+    runtime_dir().map(|dir| dir.join("control.sock"))
+}
+
+/// 1行に1つのJSON-RPC 2.0リクエスト
+#[derive(Deserialize, Debug)]
+struct Request {
+    #[serde(default)]
+    id: Value,
+    method: String,
+    #[serde(default)]
+    params: Value,
+}
+
+#[derive(Serialize, Debug)]
+struct Response {
+    jsonrpc: &'static str,
+    id: Value,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    result: Option<Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<RpcError>,
+}
+
+impl Response {
+    fn result(id: Value, result: Value) -> Self {
+        Response {
+            jsonrpc: "2.0",
+            id,
+            result: Some(result),
+            error: None,
+        }
+    }
+
+    fn error(id: Value, error: RpcError) -> Self {
+        Response {
+            jsonrpc: "2.0",
+            id,
+            result: None,
+            error: Some(error),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct RpcError {
+    pub code: i64,
+    pub message: String,
+}
+
+impl RpcError {
+    fn new(code: i64, message: impl Into<String>) -> Self {
+        RpcError {
+            code,
+            message: message.into(),
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(default)]
+struct NavigateParams {
+    /// trueならサーバーに依頼する。GUIの「Send to server」と同じ
+    upstream: bool,
+}
+
+#[derive(Deserialize, Debug)]
+struct GotoParams {
+    page: usize,
+    #[serde(default)]
+    upstream: bool,
+}
+
+/// `status`の結果
+#[derive(Serialize, Debug)]
+pub struct Status {
+    pub phase: &'static str,
+    pub connected: bool,
+    pub ws_connected: bool,
+    pub reauth_required: bool,
+    pub actuation_paused: bool,
+    pub slide_name: String,
+    pub page: usize,
+    pub step: usize,
+    pub total_pages: usize,
+}
+
+impl From<&Snapshot> for Status {
+    fn from(snapshot: &Snapshot) -> Self {
+        Status {
+            phase: snapshot.phase.label(),
+            connected: snapshot.phase.is_connected(),
+            ws_connected: snapshot.ws_connected,
+            reauth_required: snapshot.reauth_required,
+            actuation_paused: snapshot.actuation_paused,
+            slide_name: snapshot.slide_name.clone(),
+            page: snapshot.current_slide_index,
+            step: snapshot.current_step,
+            total_pages: snapshot.total_slide_count,
+        }
+    }
+}
+
+/// 制御ソケットを`runtime`上で開く。開けなくてもエージェント自体は動かし続ける
+pub fn spawn(runtime: &tokio::runtime::Handle, client: AgentClient) {
+    let Some(path) = socket_path() else {
+        warn!("No runtime directory; control socket disabled");
+        return;
+    };
+    runtime.spawn(async move {
+        if let Err(e) = serve(&path, client).await {
+            error!("Control socket stopped: {:#}", e);
+        }
+    });
+}
+
+pub async fn serve(path: &Path, client: AgentClient) -> Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+    // bindしてから権限を絞るまでの間も他のユーザーが繋げないよう、置き場所ごと閉じておく
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)?;
+        std::fs::set_permissions(parent, std::fs::Permissions::from_mode(0o700))?;
+    }
+    if UnixStream::connect(path).await.is_ok() {
+        bail!("another agent is listening on {}", path.display());
+    }
+    // 前回落ちたときのソケットファイルが残っているとbindできない
+    let _ = std::fs::remove_file(path);
+    let listener =
+        UnixListener::bind(path).with_context(|| format!("failed to bind {}", path.display()))?;
+    std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o600))?;
+    info!("Control socket listening on {}", path.display());
+
+    loop {
+        let (stream, _) = listener.accept().await?;
+        let client = client.clone();
+        tokio::spawn(async move {
+            if let Err(e) = handle_connection(stream, client).await {
+                warn!("Control connection closed: {}", e);
+            }
+        });
+    }
+}
+
+async fn handle_connection(stream: UnixStream, client: AgentClient) -> Result<()> {
+    let (reader, mut writer) = stream.into_split();
+    let mut lines = BufReader::new(reader).lines();
+    while let Some(line) = lines.next_line().await? {
+        if line.trim().is_empty() {
+            continue;
+        }
+        let request = match serde_json::from_str::<Request>(&line) {
+            Ok(request) => request,
+            Err(e) => {
+                let error = RpcError::new(PARSE_ERROR, e.to_string());
+                write_message(&mut writer, &Response::error(Value::Null, error)).await?;
+                continue;
+            }
+        };
+        if request.method == "subscribe" {
+            // 返事より先に購読しておき、その間のイベントを取りこぼさない
+            let events = client.events();
+            write_message(&mut writer, &Response::result(request.id, json!(true))).await?;
+            return stream_events(events, &mut lines, &mut writer).await;
+        }
+        let response = match dispatch(&client, &request.method, request.params) {
+            Ok(result) => Response::result(request.id, result),
+            Err(error) => Response::error(request.id, error),
+        };
+        write_message(&mut writer, &response).await?;
+    }
+    Ok(())
+}
+
+/// 購読したら以降はイベントを通知として流すだけにする。相手が閉じたら終わる
+async fn stream_events(
+    mut events: broadcast::Receiver<Event>,
+    lines: &mut Lines<BufReader<OwnedReadHalf>>,
+    writer: &mut OwnedWriteHalf,
+) -> Result<()> {
+    loop {
+        tokio::select! {
+            event = events.recv() => match event {
+                Ok(event) => {
+                    let notification = json!({
+                        "jsonrpc": "2.0",
+                        "method": "event",
+                        "params": event,
+                    });
+                    write_message(writer, &notification).await?;
+                }
+                Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                    warn!("Control subscriber lagged; skipped {} events", skipped);
+                }
+                Err(broadcast::error::RecvError::Closed) => return Ok(()),
+            },
+            line = lines.next_line() => {
+                if line?.is_none() {
+                    return Ok(());
+                }
+            }
+        }
+    }
+}
+
+async fn write_message(writer: &mut OwnedWriteHalf, message: &impl Serialize) -> Result<()> {
+    let mut bytes = serde_json::to_vec(message)?;
+    bytes.push(b'\n');
+    writer.write_all(&bytes).await?;
+    Ok(())
+}
+
+/// 操作は受け付けた時点で`true`を返す。結果は`status`か購読で確かめる
+fn dispatch(client: &AgentClient, method: &str, params: Value) -> Result<Value, RpcError> {
+    let command = match method {
+        "status" => {
+            return Ok(json!(Status::from(client.snapshot().as_ref())));
+        }
+        "position" => {
+            let snapshot = client.snapshot();
+            let page_id = snapshot
+                .pages
+                .get(snapshot.current_slide_index)
+                .map(|page| page.page_id.clone());
+            return Ok(json!({
+                "page": snapshot.current_slide_index,
+                "step": snapshot.current_step,
+                "page_id": page_id,
+                "total_pages": snapshot.total_slide_count,
+            }));
+        }
+        "reconnect" => Command::Reconnect,
+        "disconnect" => Command::Disconnect,
+        "pause" => Command::PauseActuation { paused: true },
+        "resume" => Command::PauseActuation { paused: false },
+        "next" | "prev" => {
+            let params: NavigateParams = parse_params(params)?;
+            let navigation = if method == "next" {
+                Navigation::Next
+            } else {
+                Navigation::Prev
+            };
+            Command::Navigate {
+                navigation,
+                upstream: params.upstream,
+            }
+        }
+        "goto" => {
+            let params: GotoParams = parse_params(params)?;
+            Command::Navigate {
+                navigation: Navigation::GoTo(params.page),
+                upstream: params.upstream,
+            }
+        }
+        _ => {
+            return Err(RpcError::new(
+                METHOD_NOT_FOUND,
+                format!("unknown method: {}", method),
+            ))
+        }
+    };
+    if !client.send(command) {
+        return Err(RpcError::new(AGENT_STOPPED, "agent core has stopped"));
+    }
+    Ok(json!(true))
+}
+
+fn parse_params<T: for<'de> Deserialize<'de>>(params: Value) -> Result<T, RpcError> {
+    let params = if params.is_null() { json!({}) } else { params };
+    serde_json::from_value(params).map_err(|e| RpcError::new(INVALID_PARAMS, e.to_string()))
+}
+
+/// 1行に1つのJSON-RPC 2.0応答
+#[derive(Deserialize, Debug)]
+struct Reply {
+    #[serde(default)]
+    result: Option<Value>,
+    #[serde(default)]
+    error: Option<RpcError>,
+    #[serde(default)]
+    params: Option<Value>,
+}
+
+/// 動いているエージェントにつなぐ同期クライアント。`desktop-agent ctl`が使う
+pub struct ControlClient {
+    reader: StdBufReader<StdUnixStream>,
+    writer: StdUnixStream,
+    next_id: u64,
+}
+
+impl ControlClient {
+    pub fn connect(path: &Path) -> Result<Self> {
+        let stream = StdUnixStream::connect(path).with_context(|| {
+            format!(
+                "is the agent running? failed to connect to {}",
+                path.display()
+            )
+        })?;
+        Ok(ControlClient {
+            reader: StdBufReader::new(stream.try_clone()?),
+            writer: stream,
+            next_id: 1,
+        })
+    }
+
+    pub fn call(&mut self, method: &str, params: Value) -> Result<Value> {
+        let request = json!({
+            "jsonrpc": "2.0",
+            "id": self.next_id,
+            "method": method,
+            "params": params,
+        });
+        self.next_id += 1;
+        let mut bytes = serde_json::to_vec(&request)?;
+        bytes.push(b'\n');
+        self.writer.write_all(&bytes)?;
+
+        let reply = self
+            .read_reply()?
+            .ok_or_else(|| anyhow!("agent closed the connection"))?;
+        if let Some(error) = reply.error {
+            bail!("{} ({})", error.message, error.code);
+        }
+        Ok(reply.result.unwrap_or(Value::Null))
+    }
+
+    /// `subscribe`のあとに流れてくるイベントを1つ読む。切れたら`None`
+    pub fn next_event(&mut self) -> Result<Option<Value>> {
+        Ok(self.read_reply()?.and_then(|reply| reply.params))
+    }
+
+    fn read_reply(&mut self) -> Result<Option<Reply>> {
+        let mut line = String::new();
+        if self.reader.read_line(&mut line)? == 0 {
+            return Ok(None);
+        }
+        Ok(Some(serde_json::from_str(&line)?))
+    }
+}
@@ -0,0 +1,221 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+use anyhow::Result;
+use clap::ValueEnum;
+use enigo::{Direction::Click, Key, Keyboard, Settings};
+use log::{error, info};
+use serde::{Deserialize, Serialize};
+use tokio::sync::mpsc::UnboundedSender;
+
+use crate::models::events::Event;
+
+/// デスクトップ側でスライドを操作するもの
+pub trait Controller: Send + Sync {
+    fn change_page(&self, new_page_index: usize) -> Result<()>;
+    fn next_step(&self) -> Result<()>;
+    fn prev_step(&self) -> Result<()>;
+}
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Action {
+    ChangePage(usize),
+    NextStep,
+    PrevStep,
+}
+
+/// 手動操作の種類。GUIのボタンやページ一覧から使う
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Navigation {
+    Next,
+    Prev,
+    First,
+    Last,
+    GoTo(usize),
+}
+
+/// どうやってスライドを操作するか
+#[derive(Serialize, Deserialize, ValueEnum, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum ControllerBackend {
+    /// キー入力を送る
+    #[default]
+    Keyboard,
+    /// 何も送らずログに出すだけ。リハーサルや動作確認用
+    DryRun,
+}
+
+impl ControllerBackend {
+    pub const ALL: [ControllerBackend; 2] =
+        [ControllerBackend::Keyboard, ControllerBackend::DryRun];
+
+    pub fn label(&self) -> &'static str {
+        match self {
+            ControllerBackend::Keyboard => "Keyboard",
+            ControllerBackend::DryRun => "Dry run",
+        }
+    }
+}
+
+/// 前後のステップに使うキーの組み合わせ。プレゼンソフトやリモコンの割り当てに合わせる
+#[derive(Serialize, Deserialize, ValueEnum, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum KeyProfile {
+    #[default]
+    Arrows,
+    PageUpDown,
+    SpaceBackspace,
+}
+
+impl KeyProfile {
+    pub const ALL: [KeyProfile; 3] = [
+        KeyProfile::Arrows,
+        KeyProfile::PageUpDown,
+        KeyProfile::SpaceBackspace,
+    ];
+
+    pub fn label(&self) -> &'static str {
+        match self {
+            KeyProfile::Arrows => "Arrow keys",
+            KeyProfile::PageUpDown => "Page Up / Page Down",
+            KeyProfile::SpaceBackspace => "Space / Backspace",
+        }
+    }
+
+    fn next_key(&self) -> Key {
+        match self {
+            KeyProfile::Arrows => Key::RightArrow,
+            KeyProfile::PageUpDown => Key::PageDown,
+            KeyProfile::SpaceBackspace => Key::Space,
+        }
+    }
+
+    fn prev_key(&self) -> Key {
+        match self {
+            KeyProfile::Arrows => Key::LeftArrow,
+            KeyProfile::PageUpDown => Key::PageUp,
+            KeyProfile::SpaceBackspace => Key::Backspace,
+        }
+    }
+}
+
+pub fn build_controller(
+    backend: ControllerBackend,
+    key_profile: KeyProfile,
+) -> Arc<dyn Controller> {
+    match backend {
+        ControllerBackend::Keyboard => Arc::new(KeyboardController { key_profile }),
+        ControllerBackend::DryRun => Arc::new(DryRunController),
+    }
+}
+
+/// キー入力でスライドを操作する。ページ移動は「番号 + Enter」
+pub struct KeyboardController {
+    pub key_profile: KeyProfile,
+}
+
+impl Controller for KeyboardController {
+    fn change_page(&self, new_page_index: usize) -> Result<()> {
+        let mut enigo = enigo::Enigo::new(&Settings::default())?;
+        for c in new_page_index.to_string().chars() {
+            enigo.key(Key::Unicode(c), Click)?;
+        }
+        enigo.key(Key::Return, Click)?;
+        Ok(())
+    }
+
+    fn next_step(&self) -> Result<()> {
+        let mut enigo = enigo::Enigo::new(&Settings::default())?;
+        enigo.key(self.key_profile.next_key(), Click)?;
+        Ok(())
+    }
+
+    fn prev_step(&self) -> Result<()> {
+        let mut enigo = enigo::Enigo::new(&Settings::default())?;
+        enigo.key(self.key_profile.prev_key(), Click)?;
+        Ok(())
+    }
+}
+
+pub struct DryRunController;
+
+impl Controller for DryRunController {
+    fn change_page(&self, new_page_index: usize) -> Result<()> {
+        info!("[dry run] change page to {}", new_page_index);
+        Ok(())
+    }
+
+    fn next_step(&self) -> Result<()> {
+        info!("[dry run] next step");
+        Ok(())
+    }
+
+    fn prev_step(&self) -> Result<()> {
+        info!("[dry run] prev step");
+        Ok(())
+    }
+}
+
+/// 一時停止中はスライドに触れず成功として扱う。セッションの位置は追い続ける
+pub struct PausableController {
+    inner: Arc<dyn Controller>,
+    paused: Arc<AtomicBool>,
+}
+
+impl PausableController {
+    pub fn new(inner: Arc<dyn Controller>, paused: Arc<AtomicBool>) -> Self {
+        PausableController { inner, paused }
+    }
+
+    fn is_paused(&self) -> bool {
+        self.paused.load(Ordering::Relaxed)
+    }
+}
+
+impl Controller for PausableController {
+    fn change_page(&self, new_page_index: usize) -> Result<()> {
+        if self.is_paused() {
+            info!("[paused] change page to {}", new_page_index);
+            return Ok(());
+        }
+        self.inner.change_page(new_page_index)
+    }
+
+    fn next_step(&self) -> Result<()> {
+        if self.is_paused() {
+            info!("[paused] next step");
+            return Ok(());
+        }
+        self.inner.next_step()
+    }
+
+    fn prev_step(&self) -> Result<()> {
+        if self.is_paused() {
+            info!("[paused] prev step");
+            return Ok(());
+        }
+        self.inner.prev_step()
+    }
+}
+
+/// キー入力はブロッキングなので別スレッドで実行する。順番を保つため完了まで待つ
+pub async fn execute(
+    controller: Arc<dyn Controller>,
+    action: Action,
+    sender: &UnboundedSender<Event>,
+) {
+    let result = tokio::task::spawn_blocking(move || match action {
+        Action::ChangePage(index) => controller.change_page(index),
+        Action::NextStep => controller.next_step(),
+        Action::PrevStep => controller.prev_step(),
+    })
+    .await;
+
+    let error = match result {
+        Ok(Ok(())) => return,
+        Ok(Err(e)) => e.to_string(),
+        Err(e) => format!("Controller task panicked: {}", e),
+    };
+    error!("Failed to execute {:?}: {}", action, error);
+    let _ = sender.send(Event::ActuationFailed { action, error });
+}
@@ -0,0 +1,155 @@
+use std::collections::VecDeque;
+use std::fmt::Write as _;
+use std::path::Path;
+
+use anyhow::{Context, Result};
+use chrono::{DateTime, Local};
+use serde::Serialize;
+
+/// GUIに残すログの上限。古いものから捨てる
+pub const DEFAULT_CAPACITY: usize = 2000;
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "lowercase")]
+pub enum LogLevel {
+    Info,
+    Warn,
+    Error,
+}
+
+impl LogLevel {
+    pub const ALL: [LogLevel; 3] = [LogLevel::Info, LogLevel::Warn, LogLevel::Error];
+
+    pub fn label(&self) -> &'static str {
+        match self {
+            LogLevel::Info => "info",
+            LogLevel::Warn => "warn",
+            LogLevel::Error => "error",
+        }
+    }
+}
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum LogCategory {
+    Network,
+    Actuator,
+    Vote,
+    Auth,
+}
+
+impl LogCategory {
+    pub const ALL: [LogCategory; 4] = [
+        LogCategory::Network,
+        LogCategory::Actuator,
+        LogCategory::Vote,
+        LogCategory::Auth,
+    ];
+
+    pub fn label(&self) -> &'static str {
+        match self {
+            LogCategory::Network => "network",
+            LogCategory::Actuator => "actuator",
+            LogCategory::Vote => "vote",
+            LogCategory::Auth => "auth",
+        }
+    }
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct LogEntry {
+    pub timestamp: DateTime<Local>,
+    pub level: LogLevel,
+    pub category: LogCategory,
+    pub message: String,
+    pub payload: Option<serde_json::Value>,
+}
+
+/// 発表中に起きたことの記録。切断しても消さず、後から書き出して振り返れるようにする
+#[derive(Debug)]
+pub struct EventLog {
+    entries: VecDeque<LogEntry>,
+    capacity: usize,
+}
+
+impl Default for EventLog {
+    fn default() -> Self {
+        EventLog::with_capacity(DEFAULT_CAPACITY)
+    }
+}
+
+impl LogEntry {
+    pub fn new(
+        level: LogLevel,
+        category: LogCategory,
+        message: impl Into<String>,
+        payload: Option<serde_json::Value>,
+    ) -> Self {
+        LogEntry {
+            timestamp: Local::now(),
+            level,
+            category,
+            message: message.into(),
+            payload,
+        }
+    }
+}
+
+impl EventLog {
+    pub fn with_capacity(capacity: usize) -> Self {
+        EventLog {
+            entries: VecDeque::new(),
+            capacity,
+        }
+    }
+
+    pub fn append(&mut self, entry: LogEntry) {
+        if self.entries.len() >= self.capacity {
+            self.entries.pop_front();
+        }
+        self.entries.push_back(entry);
+    }
+
+    pub fn entries(&self) -> impl DoubleEndedIterator<Item = &LogEntry> {
+        self.entries.iter()
+    }
+
+    pub fn clear(&mut self) {
+        self.entries.clear();
+    }
+
+    pub fn export_json(&self, path: &Path) -> Result<()> {
+        let entries: Vec<&LogEntry> = self.entries.iter().collect();
+        std::fs::write(path, serde_json::to_vec_pretty(&entries)?)
+            .with_context(|| format!("Failed to write {}", path.display()))
+    }
+
+    pub fn export_csv(&self, path: &Path) -> Result<()> {
+        let mut csv = String::from("timestamp,level,category,message,payload\n");
+        for entry in &self.entries {
+            let payload = entry
+                .payload
+                .as_ref()
+                .map(|payload| payload.to_string())
+                .unwrap_or_default();
+            let _ = writeln!(
+                csv,
+                "{},{},{},{},{}",
+                entry.timestamp.to_rfc3339(),
+                entry.level.label(),
+                entry.category.label(),
+                csv_field(&entry.message),
+                csv_field(&payload)
+            );
+        }
+        std::fs::write(path, csv).with_context(|| format!("Failed to write {}", path.display()))
+    }
+}
+
+fn csv_field(value: &str) -> String {
+    if value.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_owned()
+    }
+}
@@ -0,0 +1,41 @@
+use egui::{Color32, RichText};
+
+use desktop_agent::connection::ConnectionPhase;
+
+use crate::gui::state::AppState;
+
+/// 接続フォームの下に出す。段階に応じて接続・キャンセル・再試行を切り替える
+pub fn connection_progress(ui: &mut egui::Ui, state: &mut AppState) {
+    let phase = state.snapshot.phase.clone();
+    if let Some(progress) = phase.progress() {
+        ui.add(
+            egui::ProgressBar::new(progress)
+                .text(phase.label())
+                .desired_width(240.0)
+                .animate(true),
+        );
+        if ui.button("Cancel").clicked() {
+            state.cancel_connection();
+        }
+        return;
+    }
+
+    match phase {
+        ConnectionPhase::Failed { reason, .. } => {
+            ui.label(RichText::new(reason).color(Color32::from_rgb(0xdc, 0x26, 0x26)));
+            ui.horizontal(|ui| {
+                if ui.button("Retry").clicked() {
+                    state.retry_connection();
+                }
+                if ui.button("Cancel").clicked() {
+                    state.cancel_connection();
+                }
+            });
+        }
+        _ => {
+            if ui.button("Connect").clicked() {
+                state.connect_to_session();
+            }
+        }
+    }
+}
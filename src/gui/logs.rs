@@ -0,0 +1,121 @@
+use std::path::Path;
+
+use egui::{Color32, RichText};
+
+use desktop_agent::eventlog::{LogCategory, LogLevel};
+
+use crate::gui::state::AppState;
+
+/// ログ表示の絞り込み条件
+pub struct LogFilter {
+    pub min_level: LogLevel,
+    pub categories: Vec<LogCategory>,
+    pub search: String,
+    pub export_path: String,
+}
+
+impl Default for LogFilter {
+    fn default() -> Self {
+        LogFilter {
+            min_level: LogLevel::Info,
+            categories: LogCategory::ALL.to_vec(),
+            search: String::new(),
+            export_path: String::new(),
+        }
+    }
+}
+
+fn level_color(level: LogLevel) -> Option<Color32> {
+    match level {
+        LogLevel::Info => None,
+        LogLevel::Warn => Some(Color32::from_rgb(0xf5, 0x9e, 0x0b)),
+        LogLevel::Error => Some(Color32::from_rgb(0xdc, 0x26, 0x26)),
+    }
+}
+
+pub fn log_view(ui: &mut egui::Ui, state: &mut AppState) {
+    let filter = &mut state.log_filter;
+    ui.horizontal_wrapped(|ui| {
+        egui::ComboBox::from_id_salt("log_level")
+            .selected_text(format!("≥ {}", filter.min_level.label()))
+            .show_ui(ui, |ui| {
+                for level in LogLevel::ALL {
+                    ui.selectable_value(&mut filter.min_level, level, level.label());
+                }
+            });
+        for category in LogCategory::ALL {
+            let mut enabled = filter.categories.contains(&category);
+            if ui.checkbox(&mut enabled, category.label()).changed() {
+                if enabled {
+                    filter.categories.push(category);
+                } else {
+                    filter.categories.retain(|c| *c != category);
+                }
+            }
+        }
+        ui.add(
+            egui::TextEdit::singleline(&mut filter.search)
+                .hint_text("Filter...")
+                .desired_width(100.0),
+        );
+    });
+
+    ui.horizontal(|ui| {
+        ui.add(
+            egui::TextEdit::singleline(&mut state.log_filter.export_path)
+                .hint_text("/path/to/log.json or .csv")
+                .desired_width(180.0),
+        );
+        let path = state.log_filter.export_path.trim().to_owned();
+        if ui
+            .add_enabled(!path.is_empty(), egui::Button::new("Export JSON"))
+            .clicked()
+        {
+            state.status_message = match state.logs.export_json(Path::new(&path)) {
+                Ok(()) => format!("Exported log to {}.", path),
+                Err(e) => format!("Export failed: {:#}", e),
+            };
+        }
+        if ui
+            .add_enabled(!path.is_empty(), egui::Button::new("Export CSV"))
+            .clicked()
+        {
+            state.status_message = match state.logs.export_csv(Path::new(&path)) {
+                Ok(()) => format!("Exported log to {}.", path),
+                Err(e) => format!("Export failed: {:#}", e),
+            };
+        }
+        if ui.button("Clear").clicked() {
+            state.logs.clear();
+        }
+    });
+
+    let filter = &state.log_filter;
+    let search = filter.search.to_lowercase();
+    egui::ScrollArea::vertical()
+        .auto_shrink([false, false])
+        .stick_to_bottom(true)
+        .show(ui, |ui| {
+            let visible = state.logs.entries().filter(|entry| {
+                entry.level >= filter.min_level
+                    && filter.categories.contains(&entry.category)
+                    && (search.is_empty() || entry.message.to_lowercase().contains(&search))
+            });
+            for entry in visible {
+                let text = RichText::new(format!(
+                    "{} [{}] {}",
+                    entry.timestamp.format("%H:%M:%S"),
+                    entry.category.label(),
+                    entry.message
+                ));
+                let text = match level_color(entry.level) {
+                    Some(color) => text.color(color),
+                    None => text,
+                };
+                let label = ui.label(text);
+                if let Some(payload) = &entry.payload {
+                    label.on_hover_text(payload.to_string());
+                }
+            }
+        });
+}
@@ -0,0 +1,112 @@
+use desktop_agent::controller::Navigation;
+
+use crate::gui::state::AppState;
+
+/// 大文字小文字を無視した部分列一致。連続して一致するほど、単語の先頭で一致するほど高い
+pub fn fuzzy_score(query: &str, text: &str) -> Option<i32> {
+    let query: Vec<char> = query
+        .to_lowercase()
+        .chars()
+        .filter(|c| !c.is_whitespace())
+        .collect();
+    if query.is_empty() {
+        return Some(0);
+    }
+    let text: Vec<char> = text.to_lowercase().chars().collect();
+
+    let mut score = 0;
+    let mut query_index = 0;
+    let mut previous_match: Option<usize> = None;
+    for (index, c) in text.iter().enumerate() {
+        if query_index < query.len() && *c == query[query_index] {
+            score += 1;
+            if previous_match.is_some_and(|previous| previous + 1 == index) {
+                score += 5;
+            }
+            if index == 0 || !text[index - 1].is_alphanumeric() {
+                score += 3;
+            }
+            previous_match = Some(index);
+            query_index += 1;
+        }
+    }
+
+    (query_index == query.len()).then_some(score)
+}
+
+/// 前後・先頭・末尾のボタンと、検索できるページ一覧
+pub fn navigation_view(ui: &mut egui::Ui, state: &mut AppState) {
+    ui.horizontal(|ui| {
+        if ui.button("⏮ First").clicked() {
+            state.navigate(Navigation::First);
+        }
+        if ui.button("◀ Prev").clicked() {
+            state.navigate(Navigation::Prev);
+        }
+        if ui.button("Next ▶").clicked() {
+            state.navigate(Navigation::Next);
+        }
+        if ui.button("Last ⏭").clicked() {
+            state.navigate(Navigation::Last);
+        }
+        ui.separator();
+        ui.add_enabled(
+            state.snapshot.ws_connected,
+            egui::Checkbox::new(&mut state.navigate_upstream, "Send to server"),
+        )
+        .on_disabled_hover_text("Requires a WebSocket connection");
+        let mut paused = state.snapshot.actuation_paused;
+        if ui
+            .checkbox(&mut paused, "Pause actuation")
+            .on_hover_text("Keep following the session without touching the slides")
+            .changed()
+        {
+            state.set_actuation_paused(paused);
+        }
+    });
+
+    ui.collapsing("Pages", |ui| {
+        let search =
+            ui.add(egui::TextEdit::singleline(&mut state.page_search).hint_text("Search pages..."));
+
+        let mut matches: Vec<(usize, i32)> = state
+            .snapshot
+            .pages
+            .iter()
+            .enumerate()
+            .filter_map(|(index, page)| {
+                fuzzy_score(&state.page_search, &page.title).map(|score| (index, score))
+            })
+            .collect();
+        if !state.page_search.trim().is_empty() {
+            matches.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+        }
+
+        // 検索欄でEnterを押したら一番近いページへ
+        if search.lost_focus() && ui.input(|input| input.key_pressed(egui::Key::Enter)) {
+            if let Some((index, _)) = matches.first() {
+                state.navigate(Navigation::GoTo(*index));
+                state.page_search.clear();
+            }
+        }
+
+        let mut selected = None;
+        egui::ScrollArea::vertical()
+            .id_salt("page_list")
+            .max_height(200.0)
+            .show(ui, |ui| {
+                for (index, _) in &matches {
+                    let label = format!("{}. {}", index, state.snapshot.pages[*index].title);
+                    if ui
+                        .selectable_label(*index == state.snapshot.current_slide_index, label)
+                        .clicked()
+                    {
+                        selected = Some(*index);
+                    }
+                }
+            });
+        if let Some(index) = selected {
+            state.navigate(Navigation::GoTo(index));
+        }
+    });
+}
@@ -0,0 +1,53 @@
+use egui::RichText;
+
+use crate::gui::state::AppState;
+
+/// 現在のページの台本とステップ進捗、次のページの冒頭を表示する
+pub fn presenter_view(ui: &mut egui::Ui, state: &AppState) {
+    let Some(page) = state.snapshot.pages.get(state.snapshot.current_slide_index) else {
+        ui.weak("No page information available.");
+        return;
+    };
+
+    ui.group(|ui| {
+        ui.set_width(ui.available_width());
+        ui.label(RichText::new(&page.title).heading());
+
+        if page.step > 0 {
+            let current_step = state.snapshot.current_step.min(page.step);
+            ui.add(
+                egui::ProgressBar::new(current_step as f32 / page.step as f32)
+                    .text(format!("Step {}/{}", current_step, page.step)),
+            );
+        }
+
+        egui::ScrollArea::vertical()
+            .id_salt("presenter_scripts")
+            .max_height(160.0)
+            .show(ui, |ui| {
+                if page.scripts.is_empty() {
+                    ui.weak("No scripts for this page.");
+                }
+                for script in &page.scripts {
+                    ui.label(&script.content);
+                }
+            });
+
+        ui.separator();
+        match state.snapshot.pages.get(state.snapshot.current_slide_index + 1) {
+            Some(next_page) => {
+                ui.label(RichText::new(format!("Next: {}", next_page.title)).strong());
+                let first_line = next_page
+                    .scripts
+                    .first()
+                    .and_then(|script| script.content.lines().next());
+                if let Some(first_line) = first_line {
+                    ui.weak(first_line);
+                }
+            }
+            None => {
+                ui.weak("This is the last page.");
+            }
+        }
+    });
+}
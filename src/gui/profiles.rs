@@ -0,0 +1,138 @@
+use std::path::Path;
+
+use desktop_agent::controller::{ControllerBackend, KeyProfile};
+
+use crate::gui::state::AppState;
+
+/// 接続プロファイルの選択・保存と、ファイルへのインポート・エクスポート
+pub fn profiles_view(ui: &mut egui::Ui, state: &mut AppState) {
+    egui::Grid::new("controller_grid")
+        .num_columns(2)
+        .show(ui, |ui| {
+            ui.label("Controller:");
+            egui::ComboBox::from_id_salt("controller_backend")
+                .selected_text(state.controller_backend.label())
+                .show_ui(ui, |ui| {
+                    for backend in ControllerBackend::ALL {
+                        ui.selectable_value(
+                            &mut state.controller_backend,
+                            backend,
+                            backend.label(),
+                        );
+                    }
+                });
+            ui.end_row();
+
+            ui.label("Keys:");
+            egui::ComboBox::from_id_salt("key_profile")
+                .selected_text(state.key_profile.label())
+                .show_ui(ui, |ui| {
+                    for key_profile in KeyProfile::ALL {
+                        ui.selectable_value(
+                            &mut state.key_profile,
+                            key_profile,
+                            key_profile.label(),
+                        );
+                    }
+                });
+            ui.end_row();
+        });
+
+    ui.collapsing("Profiles", |ui| {
+        let mut selected = None;
+        ui.horizontal(|ui| {
+            egui::ComboBox::from_id_salt("profile_select")
+                .selected_text(if state.selected_profile.is_empty() {
+                    "(none)"
+                } else {
+                    state.selected_profile.as_str()
+                })
+                .show_ui(ui, |ui| {
+                    for profile in &state.settings.profiles {
+                        let is_default =
+                            state.settings.default_profile.as_ref() == Some(&profile.name);
+                        let label = if is_default {
+                            format!("{} (default)", profile.name)
+                        } else {
+                            profile.name.clone()
+                        };
+                        if ui
+                            .selectable_label(profile.name == state.selected_profile, label)
+                            .clicked()
+                        {
+                            selected = Some(profile.clone());
+                        }
+                    }
+                });
+
+            let has_selection = state.settings.profile(&state.selected_profile).is_some();
+            if ui
+                .add_enabled(has_selection, egui::Button::new("Set default"))
+                .clicked()
+            {
+                state.settings.default_profile = Some(state.selected_profile.clone());
+                state.save_settings();
+            }
+            if ui
+                .add_enabled(has_selection, egui::Button::new("Delete"))
+                .clicked()
+            {
+                let name = std::mem::take(&mut state.selected_profile);
+                state.settings.remove_profile(&name);
+                state.save_settings();
+            }
+        });
+        if let Some(profile) = selected {
+            state.apply_profile(&profile);
+        }
+
+        ui.horizontal(|ui| {
+            ui.add(
+                egui::TextEdit::singleline(&mut state.new_profile_name)
+                    .hint_text("Profile name")
+                    .desired_width(140.0),
+            );
+            let name = state.new_profile_name.trim().to_owned();
+            if ui
+                .add_enabled(!name.is_empty(), egui::Button::new("Save profile"))
+                .clicked()
+            {
+                let profile = state.current_profile(&name);
+                state.settings.upsert_profile(profile);
+                state.selected_profile = name;
+                state.new_profile_name.clear();
+                state.save_settings();
+            }
+        });
+
+        ui.horizontal(|ui| {
+            ui.add(
+                egui::TextEdit::singleline(&mut state.profile_transfer_path)
+                    .hint_text("/path/to/profiles.json")
+                    .desired_width(140.0),
+            );
+            let path = state.profile_transfer_path.trim().to_owned();
+            if ui
+                .add_enabled(!path.is_empty(), egui::Button::new("Import"))
+                .clicked()
+            {
+                match state.settings.import_profiles(Path::new(&path)) {
+                    Ok(count) => {
+                        state.status_message = format!("Imported {} profiles.", count);
+                        state.save_settings();
+                    }
+                    Err(e) => state.status_message = format!("Import failed: {:#}", e),
+                }
+            }
+            if ui
+                .add_enabled(!path.is_empty(), egui::Button::new("Export"))
+                .clicked()
+            {
+                state.status_message = match state.settings.export_profiles(Path::new(&path)) {
+                    Ok(()) => format!("Exported profiles to {}.", path),
+                    Err(e) => format!("Export failed: {:#}", e),
+                };
+            }
+        });
+    });
+}
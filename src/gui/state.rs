@@ -1,161 +1,201 @@
 use std::sync::Arc;
 
-use once_cell::sync::Lazy;
-use tokio::runtime::Runtime;
-
-use crate::api::auth::verify_otp;
-use crate::api::session::get_session_info;
-use crate::api::state::get_session_state;
-use crate::models::events::Event;
-use crate::models::session::SessionInfoPage;
-use crate::websocket::{run_websocket, WsHandle};
-use crate::APP_STATE;
-
-#[derive(Default)]
+use tokio::sync::mpsc::UnboundedReceiver;
+
+use desktop_agent::agent::{AgentHandle, Command, Notification, Snapshot};
+use desktop_agent::config::AgentConfig;
+use desktop_agent::controller::{ControllerBackend, KeyProfile, Navigation};
+use desktop_agent::eventlog::EventLog;
+use desktop_agent::net::NetworkSettings;
+use desktop_agent::settings::{self, ConnectionProfile, Settings};
+use desktop_agent::webhooks::WebhookHandle;
+
+use super::logs::LogFilter;
+
+/// 画面側だけが持つ状態。セッションの状態は中核から届く`snapshot`を読む
 pub struct AppState {
+    pub agent: AgentHandle,
+    pub snapshot: Arc<Snapshot>,
     pub primary_server_address: String,
-    pub session_server_address: String,
     pub otp: String,
     pub agent_name: String,
-    pub session_id: String,
-    pub token: String,
-    pub connected: bool,
     pub status_message: String,
-    pub slide_name: String,
-    pub current_slide_index: usize,
-    pub total_slide_count: usize,
-    pub current_step: usize,
-    pub pages: Vec<SessionInfoPage>,
-    pub ws_event_receiver: Option<std::sync::mpsc::Receiver<Event>>,
-    pub logs: Vec<String>,
-    pub ws_handle: Option<WsHandle>,
+    pub logs: EventLog,
+    pub log_filter: LogFilter,
+    pub network: NetworkSettings,
+    pub config: AgentConfig,
+    /// 設定ファイルを読み直した結果
+    config_updates: UnboundedReceiver<anyhow::Result<AgentConfig>>,
+    pub webhooks: WebhookHandle,
+    /// trueならサーバーに依頼し、falseならこの端末のスライドを直接操作する
+    pub navigate_upstream: bool,
+    pub page_search: String,
+    pub controller_backend: ControllerBackend,
+    pub key_profile: KeyProfile,
+    pub settings: Settings,
+    pub selected_profile: String,
+    pub new_profile_name: String,
+    pub profile_transfer_path: String,
 }
 
 impl AppState {
-    pub fn connect_to_session(&mut self) {
-        let client = reqwest::Client::new();
-        let base_url = self.primary_server_address.clone();
-        let otp = self.otp.clone();
+    pub fn new(
+        agent: AgentHandle,
+        config: AgentConfig,
+        config_updates: UnboundedReceiver<anyhow::Result<AgentConfig>>,
+        webhooks: WebhookHandle,
+    ) -> Self {
+        let snapshot = agent.snapshot();
+        let mut state = AppState {
+            agent,
+            snapshot,
+            primary_server_address: String::new(),
+            otp: String::new(),
+            agent_name: String::new(),
+            status_message: String::new(),
+            logs: EventLog::default(),
+            log_filter: LogFilter::default(),
+            network: NetworkSettings::default(),
+            config,
+            config_updates,
+            webhooks,
+            navigate_upstream: false,
+            page_search: String::new(),
+            controller_backend: ControllerBackend::default(),
+            key_profile: KeyProfile::default(),
+            settings: Settings::default(),
+            selected_profile: String::new(),
+            new_profile_name: String::new(),
+            profile_transfer_path: String::new(),
+        };
+        state.load_settings();
+        // 設定ファイルの接続設定と表示設定は、GUIで保存したものより優先する
+        let mut profile = state.current_profile(&state.selected_profile);
+        state.config.connection.apply_to(&mut profile);
+        state.apply_profile(&profile);
+        state.config.ui.apply_to(&mut state.settings.display);
+        state
+    }
 
-        std::thread::spawn(move || {
-            let rt = tokio::runtime::Runtime::new().unwrap();
-            let result = rt.block_on(verify_otp(&client, &base_url, &otp));
+    /// 中核からの通知と設定の再読み込みを取り込み、最新の状態に差し替える。毎フレーム呼ばれる。
+    /// 表示設定が変わったら`true`
+    pub fn sync(&mut self) -> bool {
+        while let Some(notification) = self.agent.try_recv() {
+            match notification {
+                Notification::Status(message) => self.status_message = message,
+                Notification::Log(entry) => self.logs.append(entry),
+            }
+        }
+        self.snapshot = self.agent.snapshot();
+        // WebSocketが切れたら、チェックが残ったまま送れない操作を出さないよう手元の操作に戻す
+        if !self.snapshot.ws_connected {
+            self.navigate_upstream = false;
+        }
 
+        let mut display_changed = false;
+        while let Ok(result) = self.config_updates.try_recv() {
             match result {
-                Ok(response) => {
-                    {
-                        let mut state = APP_STATE.lock().unwrap();
-                        state.session_id = response.session_id;
-                        state.token = response.token;
-                        state.session_server_address = response.aggregator_url;
-                        state.status_message = "OTP verified successfully.".to_owned();
-                    }
-                    APP_STATE.lock().unwrap().fetch_session_info();
-                    APP_STATE.lock().unwrap().fetch_session_state();
-                    APP_STATE.lock().unwrap().establish_ws_connection();
-                }
-                Err(e) => {
-                    let mut state = APP_STATE.lock().unwrap();
-                    state.status_message = format!("OTP Verification Failed: {}", e);
+                // 接続の設定は接続フォームに入力済みなので、ここでは反映しない
+                Ok(config) => {
+                    display_changed |= config.ui.apply_to(&mut self.settings.display);
+                    self.config = config;
+                    self.status_message = "Config reloaded".to_owned();
                 }
+                Err(e) => self.status_message = format!("Config error: {:#}", e),
             }
+        }
+        display_changed
+    }
+
+    pub fn connect_to_session(&mut self) {
+        let profile = self.current_profile(&self.selected_profile);
+        self.settings.last_used = Some(profile.clone());
+        self.save_settings();
+        self.agent.send(Command::Connect {
+            profile,
+            otp: self.otp.clone(),
+        });
+    }
+
+    pub fn retry_connection(&mut self) {
+        self.agent.send(Command::Retry {
+            otp: self.otp.clone(),
         });
     }
 
-    // Workaround
-    // 全体のStateに統合したい, そもそもなんで動いてるのかよくわかってない
-    pub fn establish_ws_connection(&mut self) {
-        if self.ws_handle.is_some() {
-            return; // すでに接続済みなら何もしない
+    pub fn cancel_connection(&mut self) {
+        self.agent.send(Command::Cancel);
+    }
+
+    /// OTPを入れ直さずに前回のセッションへ戻る。接続フォームもキャッシュの内容に合わせる
+    pub fn resume_last_session(&mut self) {
+        if let Some(cached) = &self.snapshot.resume_candidate {
+            self.primary_server_address = cached.primary_server_address.clone();
+            self.agent_name = cached.agent_name.clone();
         }
+        let profile = self.current_profile(&self.selected_profile);
+        self.agent.send(Command::ResumeLastSession { profile });
+    }
 
-        let session_id = self.session_id.clone();
-        let token = self.token.clone();
-        let agent_name = self.agent_name.clone();
-        let session_server_address = self.session_server_address.clone();
+    /// 新しいOTPで同じセッションに入り直す
+    pub fn reauthenticate(&mut self) {
+        let otp = std::mem::take(&mut self.otp);
+        self.agent.send(Command::Reauthenticate { otp });
+    }
 
-        let (sender, receiver) = std::sync::mpsc::channel();
-        self.ws_event_receiver = Some(receiver);
+    /// 手動操作。サーバー経由なら結果はWebSocketのコマンドとして戻ってくる
+    pub fn navigate(&mut self, navigation: Navigation) {
+        self.agent.send(Command::Navigate {
+            navigation,
+            upstream: self.navigate_upstream,
+        });
+    }
 
-        std::thread::spawn(move || {
-            static RUNTIME: Lazy<Arc<Runtime>> = Lazy::new(|| Arc::new(Runtime::new().unwrap()));
+    pub fn set_actuation_paused(&mut self, paused: bool) {
+        self.agent.send(Command::PauseActuation { paused });
+    }
 
-            let rt = RUNTIME.clone();
-            let result = rt.block_on(run_websocket(
-                &session_server_address,
-                &session_id,
-                &token,
-                &agent_name,
-                sender,
-            ));
+    pub fn disconnect(&mut self) {
+        self.agent.send(Command::Disconnect);
+    }
 
-            let mut state = APP_STATE.lock().unwrap();
-            match result {
-                Ok(ws_handle) => {
-                    state.ws_handle = Some(ws_handle);
-                    state.connected = true;
-                    state.status_message = "WebSocket connection established.".to_owned();
-                }
-                Err(e) => {
-                    state.connected = false;
-                    state.status_message = format!("WebSocket error: {}", e);
-                }
-            }
-        });
+    /// 既定のプロファイル、なければ最後に使った接続設定を接続フォームに入れる
+    pub fn load_settings(&mut self) {
+        self.settings = settings::load();
+        let initial = self
+            .settings
+            .default_profile()
+            .or(self.settings.last_used.as_ref())
+            .cloned();
+        if let Some(profile) = initial {
+            self.apply_profile(&profile);
+        }
     }
 
-    pub fn fetch_session_info(&mut self) {
-        let client = reqwest::Client::new();
-        let base_url = self.session_server_address.clone();
-        let session_id = self.session_id.clone();
-        let token = self.token.clone();
-        std::thread::spawn(move || {
-            let rt = tokio::runtime::Runtime::new().unwrap();
-            let result = rt.block_on(get_session_info(&client, &base_url, &session_id, &token));
-            match result {
-                Ok(response) => {
-                    let mut state = APP_STATE.lock().unwrap();
-                    state.slide_name = response.title;
-                    state.total_slide_count = response.pages.len();
-                    state.pages = response.pages;
-                }
-                Err(e) => {
-                    let mut state = APP_STATE.lock().unwrap();
-                    state.status_message = format!("Failed to fetch session info: {}", e);
-                }
-            }
-        });
+    pub fn apply_profile(&mut self, profile: &ConnectionProfile) {
+        self.selected_profile = profile.name.clone();
+        self.primary_server_address = profile.primary_server_address.clone();
+        self.agent_name = profile.agent_name.clone();
+        self.controller_backend = profile.controller_backend;
+        self.key_profile = profile.key_profile;
+        self.network = profile.network.clone();
     }
 
-    pub fn fetch_session_state(&mut self) {
-        let client = reqwest::Client::new();
-        let base_url = self.session_server_address.clone();
-        let session_id = self.session_id.clone();
-        let token = self.token.clone();
-        std::thread::spawn(move || {
-            let rt = tokio::runtime::Runtime::new().unwrap();
-            let result = rt.block_on(get_session_state(&client, &base_url, &session_id, &token));
-            match result {
-                Ok(response) => {
-                    let mut state = APP_STATE.lock().unwrap();
-                    state.current_slide_index = response.current_page as usize;
-                    state.current_step = response.current_step as usize;
-                }
-                Err(e) => {
-                    let mut state = APP_STATE.lock().unwrap();
-                    state.status_message = format!("Failed to fetch session state: {}", e);
-                }
-            }
-        });
+    /// 接続フォームの内容をプロファイルにする
+    pub fn current_profile(&self, name: &str) -> ConnectionProfile {
+        ConnectionProfile {
+            name: name.to_owned(),
+            primary_server_address: self.primary_server_address.clone(),
+            agent_name: self.agent_name.clone(),
+            controller_backend: self.controller_backend,
+            key_profile: self.key_profile,
+            network: self.network.clone(),
+        }
     }
 
-    pub fn disconnect(&mut self) {
-        if let Some(handle) = self.ws_handle.take() {
-            handle.shutdown(); // WebSocket切断実行
+    pub fn save_settings(&mut self) {
+        if let Err(e) = settings::save(&self.settings) {
+            self.status_message = format!("Failed to save settings: {}", e);
         }
-        self.connected = false;
-        self.status_message = "Disconnected".to_owned();
-        self.logs.clear();
-        self.ws_event_receiver = None;
     }
 }
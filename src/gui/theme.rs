@@ -0,0 +1,165 @@
+use egui::{Color32, Stroke};
+
+use desktop_agent::settings::{DisplaySettings, ThemeChoice, MAX_FONT_SCALE, MIN_FONT_SCALE};
+
+/// catppuccin-eguiは別バージョンのeguiに依存しているので、色だけ借りてVisualsはここで組み立てる
+fn color([r, g, b, a]: [u8; 4]) -> Color32 {
+    Color32::from_rgba_premultiplied(r, g, b, a)
+}
+
+fn widget_visuals(
+    old: egui::style::WidgetVisuals,
+    theme: &catppuccin_egui::Theme,
+    bg_fill: Color32,
+) -> egui::style::WidgetVisuals {
+    egui::style::WidgetVisuals {
+        bg_fill,
+        weak_bg_fill: bg_fill,
+        bg_stroke: Stroke {
+            color: color(theme.overlay1.to_array()),
+            ..old.bg_stroke
+        },
+        fg_stroke: Stroke {
+            color: color(theme.text.to_array()),
+            ..old.fg_stroke
+        },
+        ..old
+    }
+}
+
+fn catppuccin_visuals(theme: catppuccin_egui::Theme) -> egui::Visuals {
+    let is_latte = theme == catppuccin_egui::LATTE;
+    let old = if is_latte {
+        egui::Visuals::light()
+    } else {
+        egui::Visuals::dark()
+    };
+
+    egui::Visuals {
+        override_text_color: Some(color(theme.text.to_array())),
+        hyperlink_color: color(theme.rosewater.to_array()),
+        faint_bg_color: color(theme.surface0.to_array()),
+        extreme_bg_color: color(theme.crust.to_array()),
+        code_bg_color: color(theme.mantle.to_array()),
+        warn_fg_color: color(theme.peach.to_array()),
+        error_fg_color: color(theme.maroon.to_array()),
+        window_fill: color(theme.base.to_array()),
+        panel_fill: color(theme.base.to_array()),
+        window_stroke: Stroke {
+            color: color(theme.overlay1.to_array()),
+            ..old.window_stroke
+        },
+        widgets: egui::style::Widgets {
+            noninteractive: widget_visuals(
+                old.widgets.noninteractive,
+                &theme,
+                color(theme.base.to_array()),
+            ),
+            inactive: widget_visuals(
+                old.widgets.inactive,
+                &theme,
+                color(theme.surface0.to_array()),
+            ),
+            hovered: widget_visuals(
+                old.widgets.hovered,
+                &theme,
+                color(theme.surface2.to_array()),
+            ),
+            active: widget_visuals(old.widgets.active, &theme, color(theme.surface1.to_array())),
+            open: widget_visuals(old.widgets.open, &theme, color(theme.surface0.to_array())),
+        },
+        selection: egui::style::Selection {
+            bg_fill: color(theme.blue.to_array()).linear_multiply(if is_latte { 0.4 } else { 0.2 }),
+            stroke: Stroke {
+                color: color(theme.overlay1.to_array()),
+                ..old.selection.stroke
+            },
+        },
+        ..old
+    }
+}
+
+fn high_contrast_visuals() -> egui::Visuals {
+    let mut visuals = egui::Visuals::dark();
+    visuals.override_text_color = Some(Color32::WHITE);
+    visuals.panel_fill = Color32::BLACK;
+    visuals.window_fill = Color32::BLACK;
+    visuals.extreme_bg_color = Color32::BLACK;
+    visuals.faint_bg_color = Color32::from_gray(24);
+    visuals.selection.bg_fill = Color32::from_rgb(0xff, 0xd6, 0x00);
+    visuals.selection.stroke = Stroke::new(2.0, Color32::BLACK);
+    for widget in [
+        &mut visuals.widgets.noninteractive,
+        &mut visuals.widgets.inactive,
+        &mut visuals.widgets.hovered,
+        &mut visuals.widgets.active,
+        &mut visuals.widgets.open,
+    ] {
+        widget.bg_stroke = Stroke::new(2.0, Color32::WHITE);
+        widget.fg_stroke = Stroke::new(2.0, Color32::WHITE);
+    }
+    visuals.widgets.inactive.weak_bg_fill = Color32::BLACK;
+    visuals.widgets.inactive.bg_fill = Color32::BLACK;
+    visuals.widgets.hovered.weak_bg_fill = Color32::from_gray(48);
+    visuals.widgets.hovered.bg_fill = Color32::from_gray(48);
+    visuals
+}
+
+pub fn visuals(theme: ThemeChoice) -> egui::Visuals {
+    match theme {
+        ThemeChoice::Light => egui::Visuals::light(),
+        ThemeChoice::Dark => egui::Visuals::dark(),
+        ThemeChoice::CatppuccinLatte => catppuccin_visuals(catppuccin_egui::LATTE),
+        ThemeChoice::CatppuccinFrappe => catppuccin_visuals(catppuccin_egui::FRAPPE),
+        ThemeChoice::CatppuccinMacchiato => catppuccin_visuals(catppuccin_egui::MACCHIATO),
+        ThemeChoice::CatppuccinMocha => catppuccin_visuals(catppuccin_egui::MOCHA),
+        ThemeChoice::HighContrast => high_contrast_visuals(),
+    }
+}
+
+/// テーマと文字サイズを反映する。起動時と設定を変えたときだけ呼ぶ
+pub fn apply_display(ctx: &egui::Context, display: &DisplaySettings) {
+    let font_scale = display.font_scale.clamp(MIN_FONT_SCALE, MAX_FONT_SCALE);
+    let mut style = egui::Style {
+        visuals: visuals(display.theme),
+        ..egui::Style::default()
+    };
+    for font_id in style.text_styles.values_mut() {
+        font_id.size *= font_scale;
+    }
+    ctx.set_style(style);
+}
+
+/// 表示の変更。スライダーを動かしている間は毎フレーム反映するが、保存は離したときだけにする
+#[derive(Default)]
+pub struct DisplayChange {
+    pub apply: bool,
+    pub save: bool,
+}
+
+/// ヘッダーの「Display」メニュー
+pub fn display_menu(ui: &mut egui::Ui, display: &mut DisplaySettings) -> DisplayChange {
+    let mut change = DisplayChange::default();
+    ui.label("Theme");
+    for theme in ThemeChoice::ALL {
+        if ui
+            .radio_value(&mut display.theme, theme, theme.label())
+            .changed()
+        {
+            change.apply = true;
+            change.save = true;
+        }
+    }
+    ui.separator();
+    ui.label("Font size");
+    let slider = ui.add(
+        egui::Slider::new(&mut display.font_scale, MIN_FONT_SCALE..=MAX_FONT_SCALE)
+            .step_by(0.05)
+            .suffix("x"),
+    );
+    change.apply |= slider.changed();
+    // クリックやキー操作での変更は1回ずつなので、その場で保存する
+    change.save |=
+        slider.drag_stopped() || slider.lost_focus() || (slider.changed() && !slider.dragged());
+    change
+}
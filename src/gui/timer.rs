@@ -0,0 +1,74 @@
+use std::time::Duration;
+
+use egui::{Color32, RichText};
+
+use crate::gui::state::AppState;
+use desktop_agent::timing::{format_duration, pace, Pace};
+
+const AMBER: Color32 = Color32::from_rgb(0xf5, 0x9e, 0x0b);
+const RED: Color32 = Color32::from_rgb(0xdc, 0x26, 0x26);
+
+fn timer_text(actual: Duration, target: Option<Duration>, warning_ratio: f32) -> RichText {
+    let Some(target) = target else {
+        return RichText::new(format_duration(actual));
+    };
+    let text = RichText::new(format!(
+        "{} / {}",
+        format_duration(actual),
+        format_duration(target)
+    ));
+    match pace(actual, target, warning_ratio) {
+        Pace::OnTrack => text,
+        Pace::Warning => text.color(AMBER).strong(),
+        Pace::Over => text.color(RED).strong(),
+    }
+}
+
+/// 経過時間と表示中ページの滞在時間。目標を超えそうなら黄色、超えたら赤
+pub fn timer_view(ui: &mut egui::Ui, state: &AppState) {
+    let pacing = &state.config.pacing;
+    let page_target = |page_index: usize| {
+        state
+            .snapshot
+            .pages
+            .get(page_index)
+            .and_then(|page| pacing.pages.get(&page.page_id))
+            .map(|secs| Duration::from_secs(*secs))
+    };
+
+    ui.horizontal(|ui| {
+        ui.label("Elapsed:");
+        ui.label(timer_text(
+            state.snapshot.timer.elapsed(),
+            pacing.total_seconds.map(Duration::from_secs),
+            pacing.warning_ratio,
+        ));
+        ui.separator();
+        ui.label("This page:");
+        ui.label(timer_text(
+            state.snapshot.timer.dwell(state.snapshot.current_slide_index),
+            page_target(state.snapshot.current_slide_index),
+            pacing.warning_ratio,
+        ));
+    });
+
+    ui.collapsing("Page timings", |ui| {
+        egui::Grid::new("page_timings_grid")
+            .num_columns(2)
+            .striped(true)
+            .show(ui, |ui| {
+                for (index, page) in state.snapshot.pages.iter().enumerate() {
+                    ui.label(&page.title);
+                    ui.label(timer_text(
+                        state.snapshot.timer.dwell(index),
+                        page_target(index),
+                        pacing.warning_ratio,
+                    ));
+                    ui.end_row();
+                }
+            });
+    });
+
+    // 時計を進めるため、入力がなくても1秒ごとに描き直す
+    ui.ctx().request_repaint_after(Duration::from_secs(1));
+}
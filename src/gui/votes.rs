@@ -0,0 +1,78 @@
+use egui::{Color32, RichText};
+
+use crate::gui::state::AppState;
+
+/// 色指定がない・読めない選択肢に使う
+const DEFAULT_CHOICE_COLOR: Color32 = Color32::from_rgb(0x88, 0x88, 0x88);
+
+/// 実施中の投票を、選択肢ごとの棒グラフと得票率で表示する
+pub fn vote_dashboard(ui: &mut egui::Ui, state: &AppState) {
+    if state.snapshot.active_vote_ids.is_empty() {
+        return;
+    }
+
+    let available_votes = state
+        .snapshot
+        .session_info
+        .as_ref()
+        .map(|info| info.available_votes.as_slice())
+        .unwrap_or_default();
+
+    egui::CollapsingHeader::new(format!("Votes ({})", state.snapshot.active_vote_ids.len()))
+        .default_open(true)
+        .show(ui, |ui| {
+            for vote_id in &state.snapshot.active_vote_ids {
+                let Some(vote) = available_votes.iter().find(|vote| &vote.vote_id == vote_id)
+                else {
+                    ui.weak(format!("Unknown vote: {}", vote_id));
+                    continue;
+                };
+                let tally = state.snapshot.vote_tallies.get(vote_id);
+                let count_of = |choice_id: &str| {
+                    tally
+                        .and_then(|tally| tally.get(choice_id))
+                        .copied()
+                        .unwrap_or(0)
+                        .max(0)
+                };
+                let total: i32 = vote
+                    .choices
+                    .iter()
+                    .map(|choice| count_of(&choice.choice_id))
+                    .sum();
+
+                ui.group(|ui| {
+                    ui.set_width(ui.available_width());
+                    ui.label(RichText::new(&vote.title).strong());
+                    if let Some(description) = &vote.description {
+                        ui.weak(description);
+                    }
+
+                    for choice in &vote.choices {
+                        let count = count_of(&choice.choice_id);
+                        let ratio = if total > 0 {
+                            count as f32 / total as f32
+                        } else {
+                            0.0
+                        };
+                        let color = choice
+                            .color
+                            .as_deref()
+                            .and_then(|hex| Color32::from_hex(hex).ok())
+                            .unwrap_or(DEFAULT_CHOICE_COLOR);
+
+                        ui.horizontal(|ui| {
+                            ui.label(&choice.title)
+                                .on_hover_text(choice.description.as_deref().unwrap_or_default());
+                            ui.add(egui::ProgressBar::new(ratio).fill(color).text(format!(
+                                "{} ({:.0}%)",
+                                count,
+                                ratio * 100.0
+                            )));
+                        });
+                    }
+                    ui.label(format!("Total: {}", total));
+                });
+            }
+        });
+}
@@ -0,0 +1,53 @@
+use egui::{Color32, RichText};
+
+use desktop_agent::webhooks::DeliveryOutcome;
+
+use crate::gui::state::AppState;
+
+/// Webhookの送信結果。新しいものを上に出す
+pub fn delivery_log(ui: &mut egui::Ui, state: &AppState) {
+    if state.config.webhooks.is_empty() {
+        return;
+    }
+
+    let deliveries = state.webhooks.deliveries();
+    egui::CollapsingHeader::new(format!("Webhook deliveries ({})", deliveries.len())).show(
+        ui,
+        |ui| {
+            if ui.button("Clear").clicked() {
+                state.webhooks.clear_deliveries();
+            }
+            egui::ScrollArea::vertical()
+                .id_salt("webhook_deliveries")
+                .max_height(160.0)
+                .show(ui, |ui| {
+                    for delivery in deliveries.iter().rev() {
+                        let (result, color) = match &delivery.outcome {
+                            DeliveryOutcome::Delivered { status } => {
+                                (format!("HTTP {}", status), None)
+                            }
+                            DeliveryOutcome::Retrying { error } => (
+                                format!("{} (retrying)", error),
+                                Some(Color32::from_rgb(0xf5, 0x9e, 0x0b)),
+                            ),
+                            DeliveryOutcome::Failed { error } => {
+                                (error.clone(), Some(Color32::from_rgb(0xdc, 0x26, 0x26)))
+                            }
+                        };
+                        let text = RichText::new(format!(
+                            "{} {} #{} {}",
+                            delivery.timestamp.format("%H:%M:%S"),
+                            delivery.event,
+                            delivery.attempt,
+                            result
+                        ));
+                        let text = match color {
+                            Some(color) => text.color(color),
+                            None => text,
+                        };
+                        ui.label(text).on_hover_text(&delivery.url);
+                    }
+                });
+        },
+    );
+}
@@ -0,0 +1,180 @@
+use std::process::Stdio;
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::{bail, Context, Result};
+use log::{debug, info, warn};
+use serde_json::{json, Value};
+use tokio::io::AsyncWriteExt;
+use tokio::sync::{broadcast, watch};
+
+use crate::agent::AgentClient;
+use crate::config::HookConfig;
+use crate::models::events::Event;
+
+/// 動いているフックの設定を差し替えるための窓口
+pub struct HookHandle {
+    hooks: watch::Sender<Arc<Vec<HookConfig>>>,
+}
+
+impl HookHandle {
+    /// 設定ファイルを読み直したときに呼ぶ。次のイベントから新しい設定で動く
+    pub fn update(&self, hooks: Vec<HookConfig>) {
+        self.hooks.send_replace(Arc::new(hooks));
+    }
+}
+
+/// 中核のイベントを購読し、合うフックを実行し続ける
+pub fn spawn(
+    runtime: &tokio::runtime::Handle,
+    client: AgentClient,
+    hooks: Vec<HookConfig>,
+) -> HookHandle {
+    let (hooks_tx, hooks_rx) = watch::channel(Arc::new(hooks));
+    let events = client.events();
+    runtime.spawn(run(client, events, hooks_rx));
+    HookHandle { hooks: hooks_tx }
+}
+
+async fn run(
+    client: AgentClient,
+    mut events: broadcast::Receiver<Event>,
+    hooks: watch::Receiver<Arc<Vec<HookConfig>>>,
+) {
+    loop {
+        let event = match events.recv().await {
+            Ok(event) => event,
+            Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                warn!("Hooks fell behind; skipped {} events", skipped);
+                continue;
+            }
+            Err(broadcast::error::RecvError::Closed) => return,
+        };
+        let hooks = hooks.borrow().clone();
+        let matching: Vec<HookConfig> = hooks
+            .iter()
+            .filter(|hook| hook.events.iter().any(|name| name == event.name()))
+            .cloned()
+            .collect();
+        if matching.is_empty() {
+            continue;
+        }
+
+        let session_id = client.snapshot().session_id.clone();
+        let payload = json!({ "session_id": session_id, "event": event });
+        let env = environment(&event, &session_id);
+        // 遅いフックでイベントの受け取りが止まらないよう、1回ずつ別のタスクで動かす
+        for hook in matching {
+            let payload = payload.clone();
+            let env = env.clone();
+            tokio::spawn(async move {
+                if let Err(e) = execute(&hook, &payload, &env).await {
+                    warn!("Hook {} failed: {:#}", hook.command, e);
+                }
+            });
+        }
+    }
+}
+
+/// `DESKTOP_AGENT_EVENT`とイベントの各項目。項目名は大文字にし、文字列以外はJSONで渡す
+fn environment(event: &Event, session_id: &str) -> Vec<(String, String)> {
+    let mut env = vec![
+        ("DESKTOP_AGENT_EVENT".to_owned(), event.name().to_owned()),
+        ("DESKTOP_AGENT_SESSION_ID".to_owned(), session_id.to_owned()),
+    ];
+    if let Ok(Value::Object(fields)) = serde_json::to_value(event) {
+        for (key, value) in fields {
+            if key == "type" {
+                continue;
+            }
+            let value = match value {
+                Value::String(value) => value,
+                value => value.to_string(),
+            };
+            env.push((format!("DESKTOP_AGENT_{}", key.to_uppercase()), value));
+        }
+    }
+    env
+}
+
+/// 標準入力にイベントをJSONで渡して実行し、時間内に終わるのを待つ
+async fn execute(hook: &HookConfig, payload: &Value, env: &[(String, String)]) -> Result<()> {
+    let mut child = tokio::process::Command::new(&hook.command)
+        .args(&hook.args)
+        .envs(env.iter().map(|(key, value)| (key, value)))
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        // 時間切れで待つのをやめたら止める
+        .kill_on_drop(true)
+        .spawn()
+        .context("failed to start")?;
+
+    if let Some(mut stdin) = child.stdin.take() {
+        let mut input = serde_json::to_vec(payload)?;
+        input.push(b'\n');
+        // 標準入力を読まないフックもあるので、書けなくても続ける
+        let _ = stdin.write_all(&input).await;
+    }
+
+    let timeout = Duration::from_secs(hook.timeout_secs);
+    let output = tokio::time::timeout(timeout, child.wait_with_output())
+        .await
+        .with_context(|| format!("timed out after {}s", hook.timeout_secs))??;
+
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    if !stdout.trim().is_empty() {
+        debug!("Hook {} output: {}", hook.command, stdout.trim());
+    }
+    if !output.status.success() {
+        bail!(
+            "{}: {}",
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+    info!("Hook {} finished", hook.command);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    fn env_map(event: &Event) -> HashMap<String, String> {
+        environment(event, "s1").into_iter().collect()
+    }
+
+    #[test]
+    fn step_changed_fields_become_uppercase_variables() {
+        let env = env_map(&Event::StepChanged {
+            new_page_index: 3,
+            new_step_index: 1,
+        });
+        let expected: HashMap<String, String> = [
+            ("DESKTOP_AGENT_EVENT", "step_changed"),
+            ("DESKTOP_AGENT_SESSION_ID", "s1"),
+            ("DESKTOP_AGENT_NEW_PAGE_INDEX", "3"),
+            ("DESKTOP_AGENT_NEW_STEP_INDEX", "1"),
+        ]
+        .into_iter()
+        .map(|(key, value)| (key.to_owned(), value.to_owned()))
+        .collect();
+        // `type`はイベント名と重なるので渡さない
+        assert_eq!(env, expected);
+    }
+
+    #[test]
+    fn strings_pass_as_is_and_other_values_as_json() {
+        let env = env_map(&Event::VoteTallyChanged {
+            vote_id: "v1".to_owned(),
+            choice_votes: HashMap::from([("yes".to_owned(), 2)]),
+        });
+        assert_eq!(env["DESKTOP_AGENT_EVENT"], "vote_tally_changed");
+        assert_eq!(env["DESKTOP_AGENT_VOTE_ID"], "v1");
+        assert_eq!(env["DESKTOP_AGENT_CHOICE_VOTES"], r#"{"yes":2}"#);
+        assert!(!env.contains_key("DESKTOP_AGENT_TYPE"));
+        assert_eq!(env.len(), 4);
+    }
+}
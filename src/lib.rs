@@ -0,0 +1,27 @@
+//! PresenStudioのデスクトップエージェント。GUIとヘッドレス実行はこのクレートの薄いフロントエンドで、
+//! 社内ツールからもAPIクライアント・プロトコル定義・イベント・スライド操作をそのまま使える
+
+pub mod agent;
+pub mod api;
+pub mod audit;
+pub mod cache;
+pub mod config;
+pub mod connection;
+#[cfg(unix)]
+pub mod control;
+pub mod controller;
+pub mod eventlog;
+pub mod hooks;
+pub mod metrics;
+pub mod models;
+pub mod mqtt;
+pub mod net;
+pub mod obs;
+pub mod osc;
+pub mod paths;
+pub mod polling;
+pub mod settings;
+pub mod status;
+pub mod timing;
+pub mod webhooks;
+pub mod websocket;
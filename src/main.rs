@@ -1,26 +1,127 @@
-mod models;
-mod api;
-mod websocket;
+mod cli;
 mod gui;
 
-use once_cell::sync::Lazy;
-use std::sync::Mutex;
-use gui::{state::AppState, ui_main};
+use std::process::ExitCode;
 
-static APP_STATE: Lazy<Mutex<AppState>> = Lazy::new(|| Mutex::new(AppState::default()));
+use clap::Parser;
+use cli::{Cli, CliCommand};
+use desktop_agent::audit::AuditLog;
+use desktop_agent::config::{self, AgentConfig, ConfigOverrides};
+use desktop_agent::{agent, hooks, mqtt, obs, osc, status, webhooks};
+use gui::AgentApp;
 
-fn main() -> eframe::Result {
-    env_logger::init();
+fn main() -> ExitCode {
+    let cli = Cli::parse();
+    let overrides = cli.overrides();
+    match cli.command {
+        Some(CliCommand::Run(args)) => cli::run(args, overrides),
+        #[cfg(unix)]
+        Some(CliCommand::Ctl(args)) => cli::ctl::run(args),
+        None => match run_gui(overrides) {
+            Ok(()) => ExitCode::SUCCESS,
+            Err(e) => {
+                log::error!("GUI terminated: {}", e);
+                ExitCode::FAILURE
+            }
+        },
+    }
+}
+
+fn run_gui(overrides: ConfigOverrides) -> eframe::Result {
+    let loaded = config::load(&overrides);
+    // 読めなかったときも、その旨は既定のレベルで出す
+    loaded
+        .as_ref()
+        .map(|config| config.logging.clone())
+        .unwrap_or_default()
+        .init_logger(env_logger::Builder::from_default_env());
+    // 設定の誤りは画面にも出す。再読み込みの結果も同じ経路で届く
+    let (config_tx, config_rx) = tokio::sync::mpsc::unbounded_channel();
+    let config = match loaded {
+        Ok(config) => {
+            if let Some(path) = &config.source {
+                log::info!("Loaded config from {}", path.display());
+            }
+            config
+        }
+        Err(e) => {
+            log::error!("{:#}", e);
+            let _ = config_tx.send(Err(e));
+            AgentConfig::default()
+        }
+    };
+    let audit = AuditLog::open(&config.audit).unwrap_or_else(|e| {
+        log::error!("Audit log disabled: {:#}", e);
+        AuditLog::disabled()
+    });
+    // 通信・ポーリング・キー操作はすべてこのランタイムの上で動く
+    let runtime = tokio::runtime::Runtime::new().expect("Failed to start Tokio runtime");
+    let runtime_handle = runtime.handle().clone();
 
     let options = eframe::NativeOptions {
         viewport: egui::ViewportBuilder::default()
             .with_title("PresenStudio agent")
-            .with_inner_size([400.0, 300.0]),
+            .with_inner_size([640.0, 480.0]),
         ..Default::default()
     };
 
-    eframe::run_simple_native("PresenStudio agent", options, move |ctx, _frame| {
-        ui_main(ctx);
-    })
+    eframe::run_native(
+        "PresenStudio agent",
+        options,
+        Box::new(move |cc| {
+            let ctx = cc.egui_ctx.clone();
+            let agent = agent::spawn(&runtime_handle, audit, move || ctx.request_repaint());
+            let hooks = hooks::spawn(&runtime_handle, agent.client(), config.hooks.clone());
+            let ctx = cc.egui_ctx.clone();
+            let webhooks = webhooks::spawn(
+                &runtime_handle,
+                agent.client(),
+                config.webhooks.clone(),
+                &config.connection.network(),
+                move || ctx.request_repaint(),
+            );
+            let ctx = cc.egui_ctx.clone();
+            let reloaded_webhooks = webhooks.clone();
+            let obs = config
+                .obs
+                .enabled
+                .then(|| obs::spawn(&runtime_handle, agent.client(), config.obs.clone()));
+            let osc = config
+                .osc
+                .enabled
+                .then(|| osc::spawn(&runtime_handle, agent.client(), config.osc.clone()));
+            if config.mqtt.enabled {
+                if let Err(e) = mqtt::spawn(&runtime_handle, agent.client(), &config.mqtt) {
+                    log::error!("MQTT bridge disabled: {:#}", e);
+                }
+            }
+            config::watch(&runtime_handle, overrides, move |result| {
+                if let Ok(config) = &result {
+                    hooks.update(config.hooks.clone());
+                    reloaded_webhooks.update(config.webhooks.clone());
+                    if let Some(obs) = &obs {
+                        obs.update(config.obs.rules.clone());
+                    }
+                    if let Some(osc) = &osc {
+                        osc.update(config.osc.messages.clone());
+                    }
+                }
+                let _ = config_tx.send(result);
+                ctx.request_repaint();
+            });
+            #[cfg(unix)]
+            desktop_agent::control::spawn(&runtime_handle, agent.client());
+            if config.status_server.enabled {
+                status::spawn(&runtime_handle, config.status_server.listen, agent.client());
+            }
+            Ok(Box::new(AgentApp::new(
+                &cc.egui_ctx,
+                agent,
+                config,
+                config_rx,
+                webhooks,
+            )))
+        }),
+    )
 }
 
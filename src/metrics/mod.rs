@@ -0,0 +1,232 @@
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use crate::agent::Snapshot;
+use crate::controller::Action;
+
+/// コマンド処理と接続手順の所要時間(秒)のバケット
+const LATENCY_BUCKETS: &[f64] = &[
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+/// WebSocketの往復時間(秒)のバケット
+const RTT_BUCKETS: &[f64] = &[0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5];
+
+/// 累積バケットの数と合計。Prometheusのhistogramとして出す
+#[derive(Debug)]
+pub struct Histogram {
+    bounds: &'static [f64],
+    data: Mutex<HistogramData>,
+}
+
+#[derive(Debug, Default)]
+struct HistogramData {
+    buckets: Vec<u64>,
+    sum: f64,
+    count: u64,
+}
+
+impl Histogram {
+    fn new(bounds: &'static [f64]) -> Self {
+        Histogram {
+            bounds,
+            data: Mutex::new(HistogramData {
+                buckets: vec![0; bounds.len()],
+                ..HistogramData::default()
+            }),
+        }
+    }
+
+    pub fn observe(&self, duration: Duration) {
+        let seconds = duration.as_secs_f64();
+        let mut data = self.data.lock().unwrap();
+        for (bound, bucket) in self.bounds.iter().zip(data.buckets.iter_mut()) {
+            if seconds <= *bound {
+                *bucket += 1;
+            }
+        }
+        data.sum += seconds;
+        data.count += 1;
+    }
+
+    fn render(&self, out: &mut String, name: &str, help: &str) {
+        let data = self.data.lock().unwrap();
+        let _ = writeln!(out, "# HELP {} {}", name, help);
+        let _ = writeln!(out, "# TYPE {} histogram", name);
+        for (bound, bucket) in self.bounds.iter().zip(&data.buckets) {
+            let _ = writeln!(out, "{}_bucket{{le=\"{}\"}} {}", name, bound, bucket);
+        }
+        let _ = writeln!(out, "{}_bucket{{le=\"+Inf\"}} {}", name, data.count);
+        let _ = writeln!(out, "{}_sum {}", name, data.sum);
+        let _ = writeln!(out, "{}_count {}", name, data.count);
+    }
+}
+
+/// 中核・WebSocketのタスク・HTTPサーバーで共有する計測値
+#[derive(Debug)]
+pub struct Metrics {
+    reconnects: AtomicU64,
+    /// change_page / next_step / prev_step の順
+    commands_received: [AtomicU64; 3],
+    actuation_failures: AtomicU64,
+    last_actuation_error: Mutex<Option<String>>,
+    /// 最後に測った往復時間(マイクロ秒)。まだ測っていなければ0
+    last_rtt_micros: AtomicU64,
+    pub command_duration: Histogram,
+    pub connect_duration: Histogram,
+    pub ws_rtt: Histogram,
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Metrics {
+            reconnects: AtomicU64::new(0),
+            commands_received: Default::default(),
+            actuation_failures: AtomicU64::new(0),
+            last_actuation_error: Mutex::new(None),
+            last_rtt_micros: AtomicU64::new(0),
+            command_duration: Histogram::new(LATENCY_BUCKETS),
+            connect_duration: Histogram::new(LATENCY_BUCKETS),
+            ws_rtt: Histogram::new(RTT_BUCKETS),
+        }
+    }
+}
+
+const COMMAND_KINDS: [&str; 3] = ["change_page", "next_step", "prev_step"];
+
+fn command_kind_index(action: Action) -> usize {
+    match action {
+        Action::ChangePage(_) => 0,
+        Action::NextStep => 1,
+        Action::PrevStep => 2,
+    }
+}
+
+impl Metrics {
+    pub fn record_reconnect(&self) {
+        self.reconnects.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// サーバーから届いたコマンド。処理にかかった時間も一緒に記録する
+    pub fn record_command(&self, action: Action, duration: Duration) {
+        self.commands_received[command_kind_index(action)].fetch_add(1, Ordering::Relaxed);
+        self.command_duration.observe(duration);
+    }
+
+    pub fn record_actuation_failure(&self, error: &str) {
+        self.actuation_failures.fetch_add(1, Ordering::Relaxed);
+        *self.last_actuation_error.lock().unwrap() = Some(error.to_owned());
+    }
+
+    pub fn record_rtt(&self, rtt: Duration) {
+        self.last_rtt_micros
+            .store(rtt.as_micros() as u64, Ordering::Relaxed);
+        self.ws_rtt.observe(rtt);
+    }
+
+    pub fn actuation_failures(&self) -> u64 {
+        self.actuation_failures.load(Ordering::Relaxed)
+    }
+
+    pub fn last_actuation_error(&self) -> Option<String> {
+        self.last_actuation_error.lock().unwrap().clone()
+    }
+
+    pub fn last_rtt(&self) -> Option<Duration> {
+        match self.last_rtt_micros.load(Ordering::Relaxed) {
+            0 => None,
+            micros => Some(Duration::from_micros(micros)),
+        }
+    }
+
+    /// Prometheusのテキスト形式。状態の値は`snapshot`から取る
+    pub fn render(&self, snapshot: &Snapshot) -> String {
+        let mut out = String::new();
+        counter(
+            &mut out,
+            "desktop_agent_reconnects_total",
+            "Times the agent had to re-establish its connection",
+            self.reconnects.load(Ordering::Relaxed),
+        );
+
+        let name = "desktop_agent_commands_received_total";
+        let _ = writeln!(out, "# HELP {} Commands received from the server", name);
+        let _ = writeln!(out, "# TYPE {} counter", name);
+        for (kind, count) in COMMAND_KINDS.iter().zip(&self.commands_received) {
+            let _ = writeln!(
+                out,
+                "{}{{type=\"{}\"}} {}",
+                name,
+                kind,
+                count.load(Ordering::Relaxed)
+            );
+        }
+
+        counter(
+            &mut out,
+            "desktop_agent_actuation_failures_total",
+            "Slide actions that the controller failed to perform",
+            self.actuation_failures(),
+        );
+        self.command_duration.render(
+            &mut out,
+            "desktop_agent_command_duration_seconds",
+            "Time from receiving a command to finishing its actuation",
+        );
+        self.connect_duration.render(
+            &mut out,
+            "desktop_agent_connect_duration_seconds",
+            "Time from starting a connection attempt to being connected",
+        );
+        self.ws_rtt.render(
+            &mut out,
+            "desktop_agent_ws_rtt_seconds",
+            "WebSocket ping round-trip time",
+        );
+
+        gauge(
+            &mut out,
+            "desktop_agent_connected",
+            "Whether the agent is following a session",
+            snapshot.phase.is_connected() as u64,
+        );
+        gauge(
+            &mut out,
+            "desktop_agent_ws_connected",
+            "Whether the WebSocket is up (0 while polling)",
+            snapshot.ws_connected as u64,
+        );
+        gauge(
+            &mut out,
+            "desktop_agent_actuation_paused",
+            "Whether slide actuation is paused",
+            snapshot.actuation_paused as u64,
+        );
+        gauge(
+            &mut out,
+            "desktop_agent_page_index",
+            "Current page index",
+            snapshot.current_slide_index as u64,
+        );
+        gauge(
+            &mut out,
+            "desktop_agent_step_index",
+            "Current step index",
+            snapshot.current_step as u64,
+        );
+        out
+    }
+}
+
+fn counter(out: &mut String, name: &str, help: &str, value: u64) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} counter", name);
+    let _ = writeln!(out, "{} {}", name, value);
+}
+
+fn gauge(out: &mut String, name: &str, help: &str, value: u64) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} gauge", name);
+    let _ = writeln!(out, "{} {}", name, value);
+}
@@ -12,4 +12,24 @@ pub struct VerifyOtpResponse {
     #[serde(rename = "aggregatorUrl")]
     pub aggregator_url: String,
     pub token: String,
+    /// トークンの有効期間(秒)。サーバーが返さない場合は無期限として扱う
+    #[serde(rename = "expiresIn", default)]
+    pub expires_in: Option<u64>,
+    #[serde(rename = "refreshToken", default)]
+    pub refresh_token: Option<String>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct RefreshTokenRequest {
+    #[serde(rename = "refreshToken")]
+    pub refresh_token: String,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct RefreshTokenResponse {
+    pub token: String,
+    #[serde(rename = "expiresIn", default)]
+    pub expires_in: Option<u64>,
+    #[serde(rename = "refreshToken", default)]
+    pub refresh_token: Option<String>,
 }
@@ -1,6 +1,56 @@
-#[derive(Debug)]
+use std::collections::HashMap;
+
+use serde::Serialize;
+
+use crate::controller::Action;
+
+/// 制御ソケットの購読者にもそのままJSONで流す
+#[derive(Serialize, Debug, Clone)]
+#[serde(tag = "type", rename_all = "snake_case")]
 pub enum Event {
     ConnectionEstablished,
     SlideChanged { new_page_index: usize },
     StepChanged { new_page_index: usize, new_step_index: usize },
+    TokenExpired,
+    ConnectionLost,
+    ResyncRequired,
+    ActuationFailed { action: Action, error: String },
+    VoteStarted { vote_id: String },
+    VoteClosed { vote_id: String },
+    VoteTallyChanged { vote_id: String, choice_votes: HashMap<String, i32> },
+    /// 利用者が切断した。中核から購読者へ流すだけで、中核自身には届かない
+    Disconnected,
+}
+
+impl Event {
+    /// フックの設定などで使う名前。JSONの`type`と同じ
+    pub const NAMES: [&'static str; 11] = [
+        "connection_established",
+        "slide_changed",
+        "step_changed",
+        "token_expired",
+        "connection_lost",
+        "resync_required",
+        "actuation_failed",
+        "vote_started",
+        "vote_closed",
+        "vote_tally_changed",
+        "disconnected",
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Event::ConnectionEstablished => "connection_established",
+            Event::SlideChanged { .. } => "slide_changed",
+            Event::StepChanged { .. } => "step_changed",
+            Event::TokenExpired => "token_expired",
+            Event::ConnectionLost => "connection_lost",
+            Event::ResyncRequired => "resync_required",
+            Event::ActuationFailed { .. } => "actuation_failed",
+            Event::VoteStarted { .. } => "vote_started",
+            Event::VoteClosed { .. } => "vote_closed",
+            Event::VoteTallyChanged { .. } => "vote_tally_changed",
+            Event::Disconnected => "disconnected",
+        }
+    }
 }
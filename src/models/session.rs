@@ -1,11 +1,11 @@
 use serde::{Deserialize, Serialize};
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct SessionInfoPageScript {
     pub content: String,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct SessionInfoPage {
     #[serde(rename = "pageId")]
     pub page_id: String,
@@ -14,7 +14,7 @@ pub struct SessionInfoPage {
     pub step: usize,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct SessionInfoAvailableVoteChoice {
     #[serde(rename = "choiceId")]
     pub choice_id: String,
@@ -23,7 +23,7 @@ pub struct SessionInfoAvailableVoteChoice {
     pub color: Option<String>,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct SessionInfoAvailableVote {
     #[serde(rename = "voteId")]
     pub vote_id: String,
@@ -32,7 +32,7 @@ pub struct SessionInfoAvailableVote {
     pub choices: Vec<SessionInfoAvailableVoteChoice>,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct SessionInfoVote {
     #[serde(rename = "voteId")]
     pub vote_id: String,
@@ -51,7 +51,7 @@ pub struct SessionInfoVote {
 //     pub votes: Vec<SessionInfoVote>,
 // }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct SessionInfo {
     #[serde(rename = "sessionId")]
     pub session_id: String,
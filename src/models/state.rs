@@ -2,14 +2,14 @@ use std::collections::HashMap;
 
 use serde::{Deserialize, Serialize};
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct VoteSummary {
     #[serde(rename = "voteId")]
     pub vote_id: String,
     pub choice_votes: HashMap<String, i32>,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct SessionState {
     #[serde(rename = "currentPage")]
     pub current_page: i32,
@@ -21,7 +21,7 @@ pub struct SessionState {
     pub vote_summaries: Vec<VoteSummary>,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Vote {
     #[serde(rename = "voteId")]
     pub vote_id: String,
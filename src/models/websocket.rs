@@ -58,3 +58,32 @@ pub enum WsEvent {
         data: TriggerPrevStepData,
     },
 }
+
+/// サーバーが付ける通し番号・タイムスタンプ。古いサーバーは付けないので省略可
+#[derive(Deserialize)]
+pub struct WsEnvelope {
+    #[serde(default)]
+    pub seq: Option<u64>,
+    #[serde(default)]
+    pub timestamp: Option<i64>,
+    #[serde(flatten)]
+    pub event: WsEvent,
+}
+
+#[derive(Serialize)]
+pub struct ChangeCurrentPageRequestData {
+    #[serde(rename = "newPageIndex")]
+    pub new_page_index: usize,
+}
+
+/// エージェントからサーバーへ送る操作の依頼
+#[derive(Serialize)]
+#[serde(tag = "requestType")]
+pub enum WsRequest {
+    #[serde(rename = "CHANGE_CURRENT_PAGE")]
+    ChangeCurrentPage { data: ChangeCurrentPageRequestData },
+    #[serde(rename = "TRIGGER_NEXT_STEP")]
+    TriggerNextStep,
+    #[serde(rename = "TRIGGER_PREV_STEP")]
+    TriggerPrevStep,
+}
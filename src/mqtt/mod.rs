@@ -0,0 +1,354 @@
+use std::time::Duration;
+
+use anyhow::{anyhow, Context, Result};
+use log::{debug, info, warn};
+use reqwest::Url;
+use rumqttc::{
+    AsyncClient, Event as MqttEvent, EventLoop, LastWill, MqttOptions, Packet, Publish, QoS,
+    TlsConfiguration, Transport,
+};
+use serde_json::json;
+use tokio::sync::broadcast;
+use tokio::time::Instant;
+
+use crate::agent::{AgentClient, Command, Snapshot};
+use crate::config::MqttConfig;
+use crate::controller::Navigation;
+use crate::models::events::Event;
+
+/// ブローカーに繋がらない・切れたときに繋ぎ直すまでの間隔
+const RECONNECT_INTERVAL: Duration = Duration::from_secs(5);
+/// 状態に変化がなくても、この間隔で死活を確かめて公開し直す
+const HEALTH_INTERVAL: Duration = Duration::from_secs(30);
+const KEEP_ALIVE: Duration = Duration::from_secs(30);
+/// 送信待ちの上限。ブローカーが遅くても中核のイベントを止めないよう、超えた分は捨てる
+const REQUEST_CAPACITY: usize = 64;
+
+/// ブローカーに繋ぎ、状態を保持付きで公開しながら`<prefix>/command/+`の操作を受け付ける。
+/// 切れたら繋ぎ直す
+pub fn spawn(
+    runtime: &tokio::runtime::Handle,
+    client: AgentClient,
+    config: &MqttConfig,
+) -> Result<()> {
+    let topics = Topics {
+        prefix: config.topic_prefix.clone(),
+    };
+    let options = options(config, &topics)?;
+    let (mqtt, eventloop) = AsyncClient::new(options, REQUEST_CAPACITY);
+    let bridge = Bridge {
+        client,
+        mqtt,
+        topics,
+        upstream: config.upstream,
+        last_health: String::new(),
+    };
+    runtime.spawn(bridge.run(eventloop));
+    Ok(())
+}
+
+fn options(config: &MqttConfig, topics: &Topics) -> Result<MqttOptions> {
+    let url = Url::parse(&config.url).context("Invalid MQTT URL")?;
+    let tls = url.scheme() == "mqtts";
+    let host = url.host_str().context("MQTT URL has no host")?;
+    let port = url.port().unwrap_or(if tls { 8883 } else { 1883 });
+    let client_id = config
+        .client_id
+        .clone()
+        .unwrap_or_else(|| format!("desktop-agent-{}", std::process::id()));
+
+    let mut options = MqttOptions::new(client_id, host, port);
+    options.set_keep_alive(KEEP_ALIVE);
+    if let Some(username) = &config.username {
+        options.set_credentials(username, config.password.clone().unwrap_or_default());
+    }
+    // 落ちたりネットワークが切れたりしたら、ブローカーが代わりにofflineを出す
+    options.set_last_will(LastWill::new(
+        topics.agent("status"),
+        "offline",
+        QoS::AtLeastOnce,
+        true,
+    ));
+    if tls {
+        let tls = match &config.ca_cert {
+            Some(path) => TlsConfiguration::SimpleNative {
+                ca: std::fs::read(path)
+                    .with_context(|| format!("Failed to read CA certificate {}", path.display()))?,
+                client_auth: None,
+            },
+            None => TlsConfiguration::Native,
+        };
+        options.set_transport(Transport::tls_with_config(tls));
+    }
+    Ok(options)
+}
+
+/// `<prefix>/agent/...`と`<prefix>/session/<id>/...`
+struct Topics {
+    prefix: String,
+}
+
+impl Topics {
+    fn agent(&self, name: &str) -> String {
+        format!("{}/agent/{}", self.prefix, name)
+    }
+
+    fn session(&self, session_id: &str, name: &str) -> String {
+        format!("{}/session/{}/{}", self.prefix, session_id, name)
+    }
+
+    fn commands(&self) -> String {
+        format!("{}/command/+", self.prefix)
+    }
+
+    /// `<prefix>/command/next`なら`next`
+    fn command<'a>(&self, topic: &'a str) -> Option<&'a str> {
+        topic
+            .strip_prefix(self.prefix.as_str())?
+            .strip_prefix("/command/")
+    }
+}
+
+struct Bridge {
+    client: AgentClient,
+    mqtt: AsyncClient,
+    topics: Topics,
+    upstream: bool,
+    /// 同じ内容を何度も公開しないよう、最後に出した死活の情報を覚えておく
+    last_health: String,
+}
+
+impl Bridge {
+    async fn run(mut self, mut eventloop: EventLoop) {
+        let mut events = self.client.events();
+        let mut health = tokio::time::interval(HEALTH_INTERVAL);
+        // 失敗したら、この時刻までブローカーへ繋ぎ直さない。待つ間も中核のイベントは受け続ける
+        let mut retry_at: Option<Instant> = None;
+        loop {
+            tokio::select! {
+                notification = eventloop.poll(), if retry_at.is_none() => match notification {
+                    Ok(MqttEvent::Incoming(Packet::ConnAck(_))) => {
+                        info!("Connected to MQTT broker");
+                        self.on_connected();
+                    }
+                    Ok(MqttEvent::Incoming(Packet::Publish(publish))) => {
+                        if !self.handle_command(&publish) {
+                            return;
+                        }
+                    }
+                    Ok(_) => {}
+                    Err(e) => {
+                        warn!(
+                            "MQTT connection failed: {}; retrying in {}s",
+                            e,
+                            RECONNECT_INTERVAL.as_secs()
+                        );
+                        retry_at = Some(Instant::now() + RECONNECT_INTERVAL);
+                    }
+                },
+                _ = tokio::time::sleep_until(retry_at.unwrap_or_else(Instant::now)),
+                    if retry_at.is_some() => retry_at = None,
+                event = events.recv() => match event {
+                    Ok(event) => self.publish_event(&event),
+                    Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                        warn!("MQTT bridge fell behind; skipped {} events", skipped);
+                        // 取りこぼした分は今の状態で埋める
+                        self.publish_state(&self.client.snapshot());
+                    }
+                    Err(broadcast::error::RecvError::Closed) => return,
+                },
+                _ = health.tick() => self.publish_health(),
+            }
+        }
+    }
+
+    /// 繋がるたびに購読し直し、保持されている状態を今のものに揃える
+    fn on_connected(&mut self) {
+        if let Err(e) = self
+            .mqtt
+            .try_subscribe(self.topics.commands(), QoS::AtLeastOnce)
+        {
+            warn!("Failed to subscribe to MQTT commands: {}", e);
+        }
+        self.publish(self.topics.agent("status"), "online".to_owned());
+        self.last_health.clear();
+        self.publish_health();
+        self.publish_state(&self.client.snapshot());
+    }
+
+    fn publish_state(&self, snapshot: &Snapshot) {
+        if snapshot.session_id.is_empty() {
+            return;
+        }
+        let session_id = &snapshot.session_id;
+        self.publish(
+            self.topics.session(session_id, "page"),
+            snapshot.current_slide_index.to_string(),
+        );
+        self.publish(
+            self.topics.session(session_id, "step"),
+            snapshot.current_step.to_string(),
+        );
+        for (vote_id, tally) in &snapshot.vote_tallies {
+            self.publish(
+                self.topics
+                    .session(session_id, &format!("votes/{}", vote_id)),
+                json!(tally).to_string(),
+            );
+        }
+    }
+
+    fn publish_event(&mut self, event: &Event) {
+        let snapshot = self.client.snapshot();
+        let session_id = &snapshot.session_id;
+        if !session_id.is_empty() {
+            match event {
+                Event::SlideChanged { new_page_index } => {
+                    self.publish(
+                        self.topics.session(session_id, "page"),
+                        new_page_index.to_string(),
+                    );
+                }
+                Event::StepChanged {
+                    new_page_index,
+                    new_step_index,
+                } => {
+                    self.publish(
+                        self.topics.session(session_id, "page"),
+                        new_page_index.to_string(),
+                    );
+                    self.publish(
+                        self.topics.session(session_id, "step"),
+                        new_step_index.to_string(),
+                    );
+                }
+                Event::VoteTallyChanged {
+                    vote_id,
+                    choice_votes,
+                } => {
+                    self.publish(
+                        self.topics
+                            .session(session_id, &format!("votes/{}", vote_id)),
+                        json!(choice_votes).to_string(),
+                    );
+                }
+                _ => {}
+            }
+        }
+        // 接続の状態が変わるイベントなら、すぐに死活の情報にも反映する
+        self.publish_health();
+    }
+
+    fn publish_health(&mut self) {
+        let snapshot = self.client.snapshot();
+        let metrics = self.client.metrics();
+        let health = json!({
+            "connection": snapshot.phase.label(),
+            "connected": snapshot.phase.is_connected(),
+            "ws_connected": snapshot.ws_connected,
+            "actuation_paused": snapshot.actuation_paused,
+            "actuation_failures": metrics.actuation_failures(),
+            "last_rtt_ms": metrics.last_rtt().map(|rtt| rtt.as_secs_f64() * 1000.0),
+            "session_id": snapshot.session_id,
+        })
+        .to_string();
+        if health == self.last_health {
+            return;
+        }
+        self.publish(self.topics.agent("health"), health.clone());
+        self.last_health = health;
+    }
+
+    /// 状態はすべて保持付きで出し、後から購読したものにも今の値が届くようにする
+    fn publish(&self, topic: String, payload: String) {
+        debug!("MQTT {} {}", topic, payload);
+        if let Err(e) = self
+            .mqtt
+            .try_publish(topic, QoS::AtLeastOnce, true, payload)
+        {
+            // 切れている間は送信待ちが溢れるが、繋ぎ直したときに今の状態を出し直す
+            debug!("Dropped MQTT publish: {}", e);
+        }
+    }
+
+    /// 中核が止まっていたら`false`
+    fn handle_command(&self, publish: &Publish) -> bool {
+        // 保持されていた古い操作を、繋ぐたびに実行し直さない
+        if publish.retain {
+            debug!("Ignoring retained MQTT command on {}", publish.topic);
+            return true;
+        }
+        let Some(command) = self.topics.command(&publish.topic) else {
+            return true;
+        };
+        let navigation = match navigation(command, &publish.payload) {
+            Ok(navigation) => navigation,
+            Err(e) => {
+                warn!("Ignoring MQTT command {}: {:#}", publish.topic, e);
+                return true;
+            }
+        };
+        info!("MQTT command {}", command);
+        self.client.send(Command::Navigate {
+            navigation,
+            upstream: self.upstream,
+        })
+    }
+}
+
+/// `goto`の内容はページ番号の文字列
+fn navigation(command: &str, payload: &[u8]) -> Result<Navigation> {
+    match command {
+        "next" => Ok(Navigation::Next),
+        "prev" => Ok(Navigation::Prev),
+        "goto" => std::str::from_utf8(payload)
+            .ok()
+            .and_then(|payload| payload.trim().parse().ok())
+            .map(Navigation::GoTo)
+            .context("goto needs a page index as its payload"),
+        _ => Err(anyhow!("unknown command {:?}", command)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn topics() -> Topics {
+        Topics {
+            prefix: "studio/agent1".to_owned(),
+        }
+    }
+
+    #[test]
+    fn command_is_the_last_topic_level() {
+        let topics = topics();
+        assert_eq!(topics.command("studio/agent1/command/next"), Some("next"));
+        assert_eq!(topics.command("studio/agent1/command/goto"), Some("goto"));
+        assert_eq!(topics.command("studio/agent1/agent/status"), None);
+        assert_eq!(topics.command("studio/agent2/command/next"), None);
+        assert_eq!(topics.command("other/studio/agent1/command/next"), None);
+    }
+
+    #[test]
+    fn commands_subscribe_to_one_level_under_prefix() {
+        assert_eq!(topics().commands(), "studio/agent1/command/+");
+    }
+
+    #[test]
+    fn parses_navigation_commands() {
+        assert_eq!(navigation("next", b"").unwrap(), Navigation::Next);
+        assert_eq!(navigation("prev", b"ignored").unwrap(), Navigation::Prev);
+        assert_eq!(navigation("goto", b"3").unwrap(), Navigation::GoTo(3));
+        assert_eq!(navigation("goto", b" 12\n").unwrap(), Navigation::GoTo(12));
+    }
+
+    #[test]
+    fn rejects_bad_goto_payloads_and_unknown_commands() {
+        assert!(navigation("goto", b"").is_err());
+        assert!(navigation("goto", b"-1").is_err());
+        assert!(navigation("goto", b"next").is_err());
+        assert!(navigation("goto", &[0xff, 0xfe]).is_err());
+        assert!(navigation("first", b"").is_err());
+    }
+}
@@ -0,0 +1,493 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::{anyhow, bail, Context, Result};
+use base64::Engine;
+use futures_util::{SinkExt, StreamExt};
+use log::{debug, info, warn};
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+use tokio::net::TcpStream;
+use tokio::sync::{broadcast, watch};
+use tokio_tungstenite::tungstenite::Message;
+use tokio_tungstenite::{MaybeTlsStream, WebSocketStream};
+
+use crate::agent::{AgentClient, Snapshot};
+use crate::config::{ObsConfig, ObsRule};
+use crate::models::events::Event;
+
+/// OBSに繋がらない・切れたときに繋ぎ直すまでの間隔
+const RECONNECT_INTERVAL: Duration = Duration::from_secs(5);
+/// 1つの要求の応答を待つ時間
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+const RPC_VERSION: u64 = 1;
+
+/// obs-websocketのopコード
+const OP_HELLO: u64 = 0;
+const OP_IDENTIFY: u64 = 1;
+const OP_IDENTIFIED: u64 = 2;
+const OP_REQUEST: u64 = 6;
+const OP_REQUEST_RESPONSE: u64 = 7;
+
+/// 動いているOBS連携のルールを差し替えるための窓口
+pub struct ObsHandle {
+    rules: watch::Sender<Arc<Vec<ObsRule>>>,
+}
+
+impl ObsHandle {
+    /// 設定ファイルを読み直したときに呼ぶ。次のイベントから新しいルールで動く
+    pub fn update(&self, rules: Vec<ObsRule>) {
+        self.rules.send_replace(Arc::new(rules));
+    }
+}
+
+/// OBSに繋ぎ、中核のイベントに合うルールを実行し続ける。切れたら繋ぎ直す
+pub fn spawn(
+    runtime: &tokio::runtime::Handle,
+    client: AgentClient,
+    config: ObsConfig,
+) -> ObsHandle {
+    let (rules_tx, rules_rx) = watch::channel(Arc::new(config.rules.clone()));
+    runtime.spawn(async move {
+        loop {
+            match session(&client, &config, &rules_rx).await {
+                Ok(()) => return,
+                Err(e) => warn!(
+                    "OBS connection to {} failed: {:#}; retrying in {}s",
+                    config.url,
+                    e,
+                    RECONNECT_INTERVAL.as_secs()
+                ),
+            }
+            tokio::time::sleep(RECONNECT_INTERVAL).await;
+        }
+    });
+    ObsHandle { rules: rules_tx }
+}
+
+/// 1回の接続。中核が止まったら`Ok`で終わる
+async fn session(
+    client: &AgentClient,
+    config: &ObsConfig,
+    rules: &watch::Receiver<Arc<Vec<ObsRule>>>,
+) -> Result<()> {
+    let mut events = client.events();
+    let mut obs = Obs::connect(&config.url, config.password.as_deref()).await?;
+    info!("Connected to OBS at {}", config.url);
+
+    // 繋がる前に移ったページの分を取り戻すため、今のページのルールを当てておく
+    let snapshot = client.snapshot();
+    let mut last_page = None;
+    if snapshot.phase.is_connected() {
+        last_page = Some(snapshot.current_slide_index);
+        let rules = rules.borrow().clone();
+        let matching = page_rules(&rules, &snapshot, snapshot.current_slide_index);
+        obs.apply(&matching).await?;
+    }
+
+    loop {
+        tokio::select! {
+            event = events.recv() => {
+                let event = match event {
+                    Ok(event) => event,
+                    Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                        warn!("OBS integration fell behind; skipped {} events", skipped);
+                        continue;
+                    }
+                    Err(broadcast::error::RecvError::Closed) => return Ok(()),
+                };
+                let rules = rules.borrow().clone();
+                let mut matching: Vec<&ObsRule> = rules
+                    .iter()
+                    .filter(|rule| rule.event.as_deref() == Some(event.name()))
+                    .collect();
+                // 同じページの中でステップが進んでも、ページのルールは当て直さない
+                if let Event::SlideChanged { new_page_index }
+                | Event::StepChanged { new_page_index, .. } = event
+                {
+                    if last_page != Some(new_page_index) {
+                        last_page = Some(new_page_index);
+                        matching.extend(page_rules(&rules, &client.snapshot(), new_page_index));
+                    }
+                }
+                obs.apply(&matching).await?;
+            }
+            message = obs.next_message() => {
+                // 要求を出していない間に届くのは、購読していないイベントや死活確認だけ
+                message?;
+            }
+        }
+    }
+}
+
+fn page_rules<'a>(
+    rules: &'a [ObsRule],
+    snapshot: &Snapshot,
+    page_index: usize,
+) -> Vec<&'a ObsRule> {
+    let page_id = snapshot
+        .pages
+        .get(page_index)
+        .map(|page| page.page_id.as_str());
+    rules
+        .iter()
+        .filter(|rule| {
+            rule.page_index == Some(page_index)
+                || (rule.page_id.is_some() && rule.page_id.as_deref() == page_id)
+        })
+        .collect()
+}
+
+struct Obs {
+    stream: WebSocketStream<MaybeTlsStream<TcpStream>>,
+    next_request_id: u64,
+}
+
+impl Obs {
+    /// Hello・Identify・Identifiedの手順を済ませる
+    async fn connect(url: &str, password: Option<&str>) -> Result<Self> {
+        let (stream, _) = tokio_tungstenite::connect_async(url)
+            .await
+            .context("Failed to connect")?;
+        let mut obs = Obs {
+            stream,
+            next_request_id: 0,
+        };
+
+        let hello = obs.expect_op(OP_HELLO).await?;
+        let authentication = match hello.get("authentication") {
+            Some(challenge) => {
+                let password = password.context("OBS requires a password")?;
+                let field = |name: &str| {
+                    challenge[name]
+                        .as_str()
+                        .with_context(|| format!("Hello is missing authentication.{}", name))
+                };
+                Some(authenticate(password, field("salt")?, field("challenge")?))
+            }
+            None => None,
+        };
+        obs.send(json!({
+            "op": OP_IDENTIFY,
+            "d": {
+                "rpcVersion": RPC_VERSION,
+                "authentication": authentication,
+                // OBS側のイベントは使わない
+                "eventSubscriptions": 0,
+            },
+        }))
+        .await?;
+        obs.expect_op(OP_IDENTIFIED).await?;
+        Ok(obs)
+    }
+
+    /// ルールの操作を順に行う。OBSに断られた操作は記録して続ける
+    async fn apply(&mut self, rules: &[&ObsRule]) -> Result<()> {
+        for rule in rules {
+            if let Some(scene) = &rule.scene {
+                self.run("SetCurrentProgramScene", json!({ "sceneName": scene }))
+                    .await?;
+            }
+            if let Some(toggle) = &rule.toggle_source {
+                let item = self
+                    .run(
+                        "GetSceneItemId",
+                        json!({ "sceneName": toggle.scene, "sourceName": toggle.source }),
+                    )
+                    .await?;
+                if let Some(item_id) = item.and_then(|item| item["sceneItemId"].as_i64()) {
+                    self.run(
+                        "SetSceneItemEnabled",
+                        json!({
+                            "sceneName": toggle.scene,
+                            "sceneItemId": item_id,
+                            "sceneItemEnabled": toggle.enabled,
+                        }),
+                    )
+                    .await?;
+                }
+            }
+            if let Some(chapter) = &rule.chapter {
+                self.run("CreateRecordChapter", json!({ "chapterName": chapter }))
+                    .await?;
+            }
+        }
+        Ok(())
+    }
+
+    /// 要求を送って応答を待つ。OBSに断られたら`None`。通信の失敗だけを`Err`にする
+    async fn run(&mut self, request_type: &str, data: Value) -> Result<Option<Value>> {
+        self.next_request_id += 1;
+        let request_id = self.next_request_id.to_string();
+        self.send(json!({
+            "op": OP_REQUEST,
+            "d": {
+                "requestType": request_type,
+                "requestId": request_id,
+                "requestData": data,
+            },
+        }))
+        .await?;
+
+        let response = tokio::time::timeout(REQUEST_TIMEOUT, async {
+            loop {
+                let response = self.expect_op(OP_REQUEST_RESPONSE).await?;
+                if response["requestId"].as_str() == Some(request_id.as_str()) {
+                    return Ok::<_, anyhow::Error>(response);
+                }
+            }
+        })
+        .await
+        .with_context(|| format!("{} timed out", request_type))??;
+
+        let status = &response["requestStatus"];
+        if status["result"].as_bool() != Some(true) {
+            warn!(
+                "OBS rejected {} ({}): {}",
+                request_type,
+                status["code"],
+                status["comment"].as_str().unwrap_or("no reason given")
+            );
+            return Ok(None);
+        }
+        debug!("OBS {} succeeded", request_type);
+        Ok(Some(response["responseData"].clone()))
+    }
+
+    async fn send(&mut self, message: Value) -> Result<()> {
+        self.stream
+            .send(Message::text(message.to_string()))
+            .await
+            .context("Failed to send to OBS")
+    }
+
+    /// 指定のopが来るまで読み進め、その`d`を返す
+    async fn expect_op(&mut self, op: u64) -> Result<Value> {
+        loop {
+            let mut message = self.next_message().await?;
+            if message["op"].as_u64() == Some(op) {
+                return Ok(message["d"].take());
+            }
+        }
+    }
+
+    /// 次のJSONメッセージ。切れたら`Err`
+    async fn next_message(&mut self) -> Result<Value> {
+        loop {
+            let message = self
+                .stream
+                .next()
+                .await
+                .ok_or_else(|| anyhow!("OBS closed the connection"))??;
+            match message {
+                Message::Text(text) => {
+                    return serde_json::from_str(&text).context("Invalid message from OBS")
+                }
+                Message::Close(frame) => match frame {
+                    // 4009は認証の失敗
+                    Some(frame) => {
+                        bail!("OBS closed the connection: {} {}", frame.code, frame.reason)
+                    }
+                    None => bail!("OBS closed the connection"),
+                },
+                _ => continue,
+            }
+        }
+    }
+}
+
+/// obs-websocket v5の認証文字列。`base64(sha256(base64(sha256(password + salt)) + challenge))`
+fn authenticate(password: &str, salt: &str, challenge: &str) -> String {
+    let base64 = base64::engine::general_purpose::STANDARD;
+    let secret = base64.encode(Sha256::digest(format!("{}{}", password, salt)));
+    base64.encode(Sha256::digest(format!("{}{}", secret, challenge)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::session::SessionInfoPage;
+    use tokio::net::TcpListener;
+
+    fn rule(page_index: Option<usize>, page_id: Option<&str>, scene: &str) -> ObsRule {
+        ObsRule {
+            page_index,
+            page_id: page_id.map(str::to_owned),
+            event: None,
+            scene: Some(scene.to_owned()),
+            toggle_source: None,
+            chapter: None,
+        }
+    }
+
+    fn snapshot(page_ids: &[&str]) -> Snapshot {
+        Snapshot {
+            pages: page_ids
+                .iter()
+                .map(|page_id| SessionInfoPage {
+                    page_id: (*page_id).to_owned(),
+                    title: String::new(),
+                    scripts: Vec::new(),
+                    step: 0,
+                })
+                .collect(),
+            ..Snapshot::default()
+        }
+    }
+
+    fn scenes(rules: Vec<&ObsRule>) -> Vec<&str> {
+        rules
+            .iter()
+            .filter_map(|rule| rule.scene.as_deref())
+            .collect()
+    }
+
+    #[test]
+    fn authenticate_matches_protocol_example() {
+        // obs-websocket v5のプロトコル文書に載っている例
+        assert_eq!(
+            authenticate(
+                "supersecretpassword",
+                "lM1GncleQOaCu9lT1yeUZhFYnqhsLLP1G5lAGo3ixaI=",
+                "+IxH4CnCiqpX1rM9scsNynZzbOe4KhDeYcTNS3PDaeY=",
+            ),
+            "1Ct943GAT+6YQUUX47Ia/ncufilbe6+oD6lY+5kaCu4="
+        );
+    }
+
+    #[test]
+    fn page_rules_match_by_index_and_id() {
+        let rules = vec![
+            rule(Some(0), None, "intro"),
+            rule(None, Some("demo"), "demo"),
+            rule(Some(1), None, "second"),
+        ];
+        let snapshot = snapshot(&["title", "demo"]);
+
+        assert_eq!(scenes(page_rules(&rules, &snapshot, 0)), ["intro"]);
+        assert_eq!(scenes(page_rules(&rules, &snapshot, 1)), ["demo", "second"]);
+        // デッキにないページは番号のルールだけで決まる
+        assert!(page_rules(&rules, &snapshot, 5).is_empty());
+    }
+
+    #[test]
+    fn page_rules_without_page_fields_never_match() {
+        let rules = vec![ObsRule {
+            event: Some("vote_started".to_owned()),
+            ..rule(None, None, "vote")
+        }];
+        assert!(page_rules(&rules, &snapshot(&["title"]), 0).is_empty());
+        assert!(page_rules(&rules, &Snapshot::default(), 0).is_empty());
+    }
+
+    async fn read_json(stream: &mut WebSocketStream<TcpStream>) -> Value {
+        loop {
+            match stream.next().await.unwrap().unwrap() {
+                Message::Text(text) => return serde_json::from_str(&text).unwrap(),
+                _ => continue,
+            }
+        }
+    }
+
+    async fn send_json(stream: &mut WebSocketStream<TcpStream>, message: Value) {
+        stream
+            .send(Message::text(message.to_string()))
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn identifies_with_password_and_reports_rejected_requests() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("ws://{}", listener.local_addr().unwrap());
+        let salt = "lM1GncleQOaCu9lT1yeUZhFYnqhsLLP1G5lAGo3ixaI=";
+        let challenge = "+IxH4CnCiqpX1rM9scsNynZzbOe4KhDeYcTNS3PDaeY=";
+
+        let server = tokio::spawn(async move {
+            let (tcp, _) = listener.accept().await.unwrap();
+            let mut stream = tokio_tungstenite::accept_async(tcp).await.unwrap();
+            send_json(
+                &mut stream,
+                json!({
+                    "op": OP_HELLO,
+                    "d": {
+                        "obsWebSocketVersion": "5.0.0",
+                        "rpcVersion": 1,
+                        "authentication": { "salt": salt, "challenge": challenge },
+                    },
+                }),
+            )
+            .await;
+
+            let identify = read_json(&mut stream).await;
+            assert_eq!(identify["op"], OP_IDENTIFY);
+            assert_eq!(identify["d"]["rpcVersion"], RPC_VERSION);
+            assert_eq!(
+                identify["d"]["authentication"],
+                "1Ct943GAT+6YQUUX47Ia/ncufilbe6+oD6lY+5kaCu4="
+            );
+            send_json(
+                &mut stream,
+                json!({ "op": OP_IDENTIFIED, "d": { "negotiatedRpcVersion": 1 } }),
+            )
+            .await;
+
+            let request = read_json(&mut stream).await;
+            assert_eq!(request["op"], OP_REQUEST);
+            assert_eq!(request["d"]["requestType"], "SetCurrentProgramScene");
+            assert_eq!(request["d"]["requestData"]["sceneName"], "missing");
+            send_json(
+                &mut stream,
+                json!({
+                    "op": OP_REQUEST_RESPONSE,
+                    "d": {
+                        "requestType": "SetCurrentProgramScene",
+                        "requestId": request["d"]["requestId"],
+                        "requestStatus": {
+                            "result": false,
+                            "code": 600,
+                            "comment": "No source was found by the name of `missing`.",
+                        },
+                    },
+                }),
+            )
+            .await;
+        });
+
+        let mut obs = Obs::connect(&url, Some("supersecretpassword"))
+            .await
+            .unwrap();
+        let response = obs
+            .run("SetCurrentProgramScene", json!({ "sceneName": "missing" }))
+            .await
+            .unwrap();
+        assert!(response.is_none());
+        server.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn refuses_to_identify_without_password() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("ws://{}", listener.local_addr().unwrap());
+        tokio::spawn(async move {
+            let (tcp, _) = listener.accept().await.unwrap();
+            let mut stream = tokio_tungstenite::accept_async(tcp).await.unwrap();
+            send_json(
+                &mut stream,
+                json!({
+                    "op": OP_HELLO,
+                    "d": {
+                        "rpcVersion": 1,
+                        "authentication": { "salt": "salt", "challenge": "challenge" },
+                    },
+                }),
+            )
+            .await;
+            // 切られるまで待つ
+            while stream.next().await.is_some() {}
+        });
+
+        let error = Obs::connect(&url, None).await.err().unwrap();
+        assert!(error.to_string().contains("requires a password"));
+    }
+}
@@ -0,0 +1,339 @@
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::Arc;
+
+use anyhow::{bail, Context, Result};
+use log::{debug, error, info, warn};
+use serde_json::Value;
+use tokio::net::UdpSocket;
+use tokio::sync::{broadcast, watch};
+
+use crate::agent::{AgentClient, Command};
+use crate::config::{OscConfig, OscMessageConfig};
+use crate::controller::Navigation;
+use crate::models::events::Event;
+
+/// 受け取るパケットの上限。OSCは1つのUDPデータグラムに収まる
+const MAX_PACKET_SIZE: usize = 65_536;
+
+/// 動いているOSC連携の送る内容を差し替えるための窓口
+pub struct OscHandle {
+    messages: watch::Sender<Arc<Vec<OscMessageConfig>>>,
+}
+
+impl OscHandle {
+    /// 設定ファイルを読み直したときに呼ぶ。次のイベントから新しい設定で送る
+    pub fn update(&self, messages: Vec<OscMessageConfig>) {
+        self.messages.send_replace(Arc::new(messages));
+    }
+}
+
+/// `target`があればイベントを送り、`listen`があれば前後の操作を受け付ける
+pub fn spawn(
+    runtime: &tokio::runtime::Handle,
+    client: AgentClient,
+    config: OscConfig,
+) -> OscHandle {
+    let (messages_tx, messages_rx) = watch::channel(Arc::new(config.messages.clone()));
+    if let Some(target) = config.target {
+        let events = client.events();
+        runtime.spawn(async move {
+            if let Err(e) = send_events(target, events, messages_rx).await {
+                error!("OSC output to {} stopped: {:#}", target, e);
+            }
+        });
+    }
+    if let Some(listen) = config.listen {
+        runtime.spawn(async move {
+            if let Err(e) = receive_commands(listen, &config, &client).await {
+                error!("OSC input on {} stopped: {:#}", listen, e);
+            }
+        });
+    }
+    OscHandle {
+        messages: messages_tx,
+    }
+}
+
+async fn send_events(
+    target: SocketAddr,
+    mut events: broadcast::Receiver<Event>,
+    messages: watch::Receiver<Arc<Vec<OscMessageConfig>>>,
+) -> Result<()> {
+    let local: SocketAddr = if target.is_ipv4() {
+        (Ipv4Addr::UNSPECIFIED, 0).into()
+    } else {
+        (Ipv6Addr::UNSPECIFIED, 0).into()
+    };
+    let socket = UdpSocket::bind(local).await?;
+    info!("Sending OSC to {}", target);
+
+    loop {
+        let event = match events.recv().await {
+            Ok(event) => event,
+            Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                warn!("OSC output fell behind; skipped {} events", skipped);
+                continue;
+            }
+            Err(broadcast::error::RecvError::Closed) => return Ok(()),
+        };
+        let messages = messages.borrow().clone();
+        let fields = match serde_json::to_value(&event) {
+            Ok(Value::Object(fields)) => fields,
+            _ => continue,
+        };
+        for message in messages
+            .iter()
+            .filter(|message| message.event == event.name())
+        {
+            let args: Vec<OscArg> = message
+                .args
+                .iter()
+                .map(|arg| OscArg::from_template(arg, &fields))
+                .collect();
+            let packet = encode(&message.address, &args);
+            // 受け手がいなくても送り続ける。UDPなので届いたかは分からない
+            match socket.send_to(&packet, target).await {
+                Ok(_) => debug!("Sent OSC {} {:?}", message.address, args),
+                Err(e) => warn!("Failed to send OSC {}: {}", message.address, e),
+            }
+        }
+    }
+}
+
+async fn receive_commands(
+    listen: SocketAddr,
+    config: &OscConfig,
+    client: &AgentClient,
+) -> Result<()> {
+    let socket = UdpSocket::bind(listen)
+        .await
+        .with_context(|| format!("Failed to bind {}", listen))?;
+    info!("Listening for OSC on {}", listen);
+
+    let mut buf = vec![0; MAX_PACKET_SIZE];
+    loop {
+        let (len, from) = socket.recv_from(&mut buf).await?;
+        let addresses = match decode_addresses(&buf[..len]) {
+            Ok(addresses) => addresses,
+            Err(e) => {
+                warn!("Ignoring malformed OSC packet from {}: {:#}", from, e);
+                continue;
+            }
+        };
+        for address in addresses {
+            let navigation = if address == config.next_address {
+                Navigation::Next
+            } else if address == config.prev_address {
+                Navigation::Prev
+            } else {
+                debug!("Ignoring OSC {} from {}", address, from);
+                continue;
+            };
+            info!("OSC {} from {}", address, from);
+            let command = Command::Navigate {
+                navigation,
+                upstream: config.upstream,
+            };
+            if !client.send(command) {
+                return Ok(());
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum OscArg {
+    Int(i32),
+    Float(f32),
+    String(String),
+}
+
+impl OscArg {
+    /// `{field}`ならイベントの項目に置き換える。数値に読めるものは数値にする
+    fn from_template(template: &str, fields: &serde_json::Map<String, Value>) -> Self {
+        let field = template
+            .strip_prefix('{')
+            .and_then(|rest| rest.strip_suffix('}'))
+            .and_then(|name| fields.get(name));
+        let text = match field {
+            Some(Value::String(value)) => return OscArg::String(value.clone()),
+            Some(value) => value.to_string(),
+            None => template.to_owned(),
+        };
+        if let Ok(value) = text.parse() {
+            OscArg::Int(value)
+        } else if let Ok(value) = text.parse() {
+            OscArg::Float(value)
+        } else {
+            OscArg::String(text)
+        }
+    }
+
+    fn tag(&self) -> char {
+        match self {
+            OscArg::Int(_) => 'i',
+            OscArg::Float(_) => 'f',
+            OscArg::String(_) => 's',
+        }
+    }
+}
+
+fn encode(address: &str, args: &[OscArg]) -> Vec<u8> {
+    let mut packet = Vec::new();
+    push_string(&mut packet, address);
+    let tags: String = std::iter::once(',')
+        .chain(args.iter().map(OscArg::tag))
+        .collect();
+    push_string(&mut packet, &tags);
+    for arg in args {
+        match arg {
+            OscArg::Int(value) => packet.extend_from_slice(&value.to_be_bytes()),
+            OscArg::Float(value) => packet.extend_from_slice(&value.to_be_bytes()),
+            OscArg::String(value) => push_string(&mut packet, value),
+        }
+    }
+    packet
+}
+
+/// 終端のNULを付け、4バイト境界まで詰める
+fn push_string(packet: &mut Vec<u8>, value: &str) {
+    packet.extend_from_slice(value.as_bytes());
+    packet.push(0);
+    while !packet.len().is_multiple_of(4) {
+        packet.push(0);
+    }
+}
+
+/// パケットに含まれるメッセージのアドレス。引数は使わないので読まない
+fn decode_addresses(packet: &[u8]) -> Result<Vec<String>> {
+    let Some(bundle) = packet.strip_prefix(b"#bundle\0") else {
+        return Ok(vec![read_string(packet)?]);
+    };
+    // 実行時刻の指定は無視し、届いたらすぐに実行する
+    let mut rest = bundle.get(8..).context("truncated bundle")?;
+    let mut addresses = Vec::new();
+    while !rest.is_empty() {
+        let size = rest.get(..4).context("truncated bundle element")?;
+        let size = u32::from_be_bytes(size.try_into()?) as usize;
+        let element = rest.get(4..4 + size).context("truncated bundle element")?;
+        addresses.extend(decode_addresses(element)?);
+        rest = &rest[4 + size..];
+    }
+    Ok(addresses)
+}
+
+fn read_string(data: &[u8]) -> Result<String> {
+    let Some(end) = data.iter().position(|byte| *byte == 0) else {
+        bail!("unterminated string");
+    };
+    let address = std::str::from_utf8(&data[..end])?;
+    if !address.starts_with('/') {
+        bail!("invalid address {:?}", address);
+    }
+    Ok(address.to_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn bundle(elements: &[Vec<u8>]) -> Vec<u8> {
+        let mut packet = b"#bundle\0".to_vec();
+        // 即時実行を表すタイムタグ
+        packet.extend_from_slice(&1u64.to_be_bytes());
+        for element in elements {
+            packet.extend_from_slice(&(element.len() as u32).to_be_bytes());
+            packet.extend_from_slice(element);
+        }
+        packet
+    }
+
+    #[test]
+    fn encodes_int_message() {
+        let packet = encode("/presen/page", &[OscArg::Int(5)]);
+        let mut expected = b"/presen/page\0\0\0\0".to_vec();
+        expected.extend_from_slice(b",i\0\0");
+        expected.extend_from_slice(&[0, 0, 0, 5]);
+        assert_eq!(packet, expected);
+    }
+
+    #[test]
+    fn encodes_float_and_string_args() {
+        let packet = encode("/a", &[OscArg::Float(1.5), OscArg::String("hi".to_owned())]);
+        let mut expected = b"/a\0\0,fs\0".to_vec();
+        expected.extend_from_slice(&1.5f32.to_be_bytes());
+        expected.extend_from_slice(b"hi\0\0");
+        assert_eq!(packet, expected);
+    }
+
+    #[test]
+    fn pads_strings_to_four_bytes_with_a_terminator() {
+        let padded = |value: &str| {
+            let mut packet = Vec::new();
+            push_string(&mut packet, value);
+            packet
+        };
+        assert_eq!(padded("abc"), b"abc\0");
+        assert_eq!(padded("abcd"), b"abcd\0\0\0\0");
+        assert_eq!(padded("abcde"), b"abcde\0\0\0");
+    }
+
+    #[test]
+    fn fills_templates_from_event_fields() {
+        let fields = json!({ "new_page_index": 5, "ratio": 0.5, "vote_id": "12" });
+        let fields = fields.as_object().unwrap();
+        let arg = |template: &str| OscArg::from_template(template, fields);
+
+        assert_eq!(arg("{new_page_index}"), OscArg::Int(5));
+        assert_eq!(arg("{ratio}"), OscArg::Float(0.5));
+        // 文字列の項目は数字だけでも文字列のまま送る
+        assert_eq!(arg("{vote_id}"), OscArg::String("12".to_owned()));
+        assert_eq!(arg("7"), OscArg::Int(7));
+        assert_eq!(arg("2.5"), OscArg::Float(2.5));
+        assert_eq!(arg("go"), OscArg::String("go".to_owned()));
+        assert_eq!(arg("{missing}"), OscArg::String("{missing}".to_owned()));
+    }
+
+    #[test]
+    fn decodes_single_message_address() {
+        let packet = encode("/presen/next", &[OscArg::Int(1)]);
+        assert_eq!(decode_addresses(&packet).unwrap(), ["/presen/next"]);
+    }
+
+    #[test]
+    fn decodes_nested_bundles_in_order() {
+        let inner = bundle(&[encode("/b", &[]), encode("/c", &[])]);
+        let outer = bundle(&[encode("/a", &[]), inner, encode("/d", &[])]);
+        assert_eq!(decode_addresses(&outer).unwrap(), ["/a", "/b", "/c", "/d"]);
+        assert!(decode_addresses(&bundle(&[])).unwrap().is_empty());
+    }
+
+    #[test]
+    fn rejects_truncated_bundles() {
+        // タイムタグが欠けている
+        assert!(decode_addresses(b"#bundle\0\0\0\0\0").is_err());
+
+        let mut packet = bundle(&[encode("/a", &[])]);
+        packet.truncate(packet.len() - 1);
+        assert!(decode_addresses(&packet).is_err());
+
+        // 要素の長さが残りより大きい
+        let mut packet = bundle(&[]);
+        packet.extend_from_slice(&u32::MAX.to_be_bytes());
+        packet.extend_from_slice(b"/a\0\0");
+        assert!(decode_addresses(&packet).is_err());
+
+        // 長さの途中で切れている
+        let mut packet = bundle(&[]);
+        packet.extend_from_slice(&[0, 0]);
+        assert!(decode_addresses(&packet).is_err());
+    }
+
+    #[test]
+    fn rejects_invalid_addresses() {
+        assert!(decode_addresses(b"presen/next\0").is_err());
+        assert!(decode_addresses(b"/presen/next").is_err());
+        assert!(decode_addresses(&bundle(&[b"next\0\0\0\0".to_vec()])).is_err());
+    }
+}
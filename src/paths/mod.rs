@@ -0,0 +1,57 @@
+use std::path::PathBuf;
+
+use directories::ProjectDirs;
+
+fn project_dirs() -> Option<ProjectDirs> {
+    ProjectDirs::from("", "PresenStudio", "desktop-agent")
+}
+
+/// キャッシュなど、消えても困らないが再起動をまたいで残したいデータの置き場所
+pub fn data_dir() -> Option<PathBuf> {
+    project_dirs().map(|dirs| dirs.data_dir().to_path_buf())
+}
+
+/// ユーザーが編集する設定ファイルの置き場所
+pub fn config_dir() -> Option<PathBuf> {
+    project_dirs().map(|dirs| dirs.config_dir().to_path_buf())
+}
+
+/// ソケットなど、実行中だけ意味のあるファイルの置き場所。なければデータ用の場所を使う
+pub fn runtime_dir() -> Option<PathBuf> {
+    project_dirs()
+        .and_then(|dirs| dirs.runtime_dir().map(|dir| dir.to_path_buf()))
+        .or_else(data_dir)
+}
+
+/// 自分以外のユーザーから読めない状態でファイルを書き込む
+pub fn write_private(path: &std::path::Path, contents: &[u8]) -> std::io::Result<()> {
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)?;
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            std::fs::set_permissions(parent, std::fs::Permissions::from_mode(0o700))?;
+        }
+    }
+    write_private_file(path, contents)
+}
+
+/// `write_private`と同じだが、置き場所のディレクトリには手を付けない。
+/// 利用者が選んだ書き出し先など、自分のものではないディレクトリに書くときに使う
+pub fn write_private_file(path: &std::path::Path, contents: &[u8]) -> std::io::Result<()> {
+    // 途中で落ちても壊れたファイルが残らないよう、一時ファイルに書いてから置き換える
+    let tmp_path = path.with_extension("tmp");
+    {
+        let mut options = std::fs::OpenOptions::new();
+        options.write(true).create(true).truncate(true);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::OpenOptionsExt;
+            options.mode(0o600);
+        }
+        let mut file = options.open(&tmp_path)?;
+        std::io::Write::write_all(&mut file, contents)?;
+        file.sync_all()?;
+    }
+    std::fs::rename(tmp_path, path)
+}
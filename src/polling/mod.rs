@@ -0,0 +1,194 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use log::{info, warn};
+use tokio::sync::mpsc::UnboundedSender;
+
+use crate::api::auth::is_token_expired;
+use crate::api::state::get_session_state;
+use crate::controller::{execute, Action, Controller};
+use crate::models::events::Event;
+use crate::models::state::SessionState;
+
+/// WebSocketが使えないネットワーク向けに、`get_session_state`の差分から操作を組み立てる
+pub struct PollHandle {
+    shutdown_tx: tokio::sync::oneshot::Sender<()>,
+}
+
+impl PollHandle {
+    pub fn shutdown(self) {
+        info!("Shutting down state polling");
+        let _ = self.shutdown_tx.send(());
+    }
+}
+
+/// 直前に把握していたページ・ステップ
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Position {
+    pub page_index: usize,
+    pub step_index: usize,
+}
+
+impl From<&SessionState> for Position {
+    fn from(state: &SessionState) -> Self {
+        Position {
+            page_index: state.current_page.max(0) as usize,
+            step_index: state.current_step.max(0) as usize,
+        }
+    }
+}
+
+/// 2つのスナップショットの差分を、実行すべき操作とGUIへ流すイベントに変換する
+pub fn diff_positions(prev: Position, next: Position) -> (Vec<Action>, Vec<Event>) {
+    let mut actions = Vec::new();
+    let mut events = Vec::new();
+
+    let mut step_from = prev.step_index;
+    if next.page_index != prev.page_index {
+        actions.push(Action::ChangePage(next.page_index));
+        events.push(Event::SlideChanged {
+            new_page_index: next.page_index,
+        });
+        // ページ番号で移動すると先頭ステップから始まる
+        step_from = 0;
+    }
+
+    if next.step_index > step_from {
+        actions.extend(std::iter::repeat_n(
+            Action::NextStep,
+            next.step_index - step_from,
+        ));
+    } else {
+        actions.extend(std::iter::repeat_n(
+            Action::PrevStep,
+            step_from - next.step_index,
+        ));
+    }
+    if next.step_index != prev.step_index || next.page_index != prev.page_index {
+        events.push(Event::StepChanged {
+            new_page_index: next.page_index,
+            new_step_index: next.step_index,
+        });
+    }
+
+    (actions, events)
+}
+
+/// 投票の開始・終了と集計の変化をイベントにする
+pub fn diff_votes(prev: &SessionState, next: &SessionState) -> Vec<Event> {
+    let mut events = Vec::new();
+
+    for vote_id in &next.active_vote_ids {
+        if !prev.active_vote_ids.contains(vote_id) {
+            events.push(Event::VoteStarted {
+                vote_id: vote_id.clone(),
+            });
+        }
+    }
+    for vote_id in &prev.active_vote_ids {
+        if !next.active_vote_ids.contains(vote_id) {
+            events.push(Event::VoteClosed {
+                vote_id: vote_id.clone(),
+            });
+        }
+    }
+
+    for summary in &next.vote_summaries {
+        let previous = prev
+            .vote_summaries
+            .iter()
+            .find(|prev_summary| prev_summary.vote_id == summary.vote_id);
+        if previous.map(|p| &p.choice_votes) != Some(&summary.choice_votes) {
+            events.push(Event::VoteTallyChanged {
+                vote_id: summary.vote_id.clone(),
+                choice_votes: summary.choice_votes.clone(),
+            });
+        }
+    }
+
+    events
+}
+
+/// `Follow`はページ・ステップの変化をデッキに反映する(WebSocketの代わり)。
+/// `Observe`はWebSocket接続中に投票の集計だけを追う
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PollMode {
+    Follow,
+    Observe,
+}
+
+/// ポーリング先のセッションと認証情報
+pub struct PollTarget {
+    pub client: reqwest::Client,
+    pub base_url: String,
+    pub session_id: String,
+    pub token: String,
+}
+
+pub fn run_polling(
+    target: PollTarget,
+    interval: Duration,
+    mode: PollMode,
+    initial: Position,
+    controller: Arc<dyn Controller>,
+    sender: UnboundedSender<Event>,
+) -> PollHandle {
+    let (shutdown_tx, mut shutdown_rx) = tokio::sync::oneshot::channel();
+
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(interval);
+        let mut position = initial;
+        let mut previous_state: Option<SessionState> = None;
+
+        loop {
+            tokio::select! {
+                _ = ticker.tick() => {},
+                _ = &mut shutdown_rx => {
+                    info!("State polling shutdown requested");
+                    break;
+                }
+            }
+
+            let state = match get_session_state(
+                &target.client,
+                &target.base_url,
+                &target.session_id,
+                &target.token,
+            )
+            .await
+            {
+                Ok(state) => state,
+                Err(e) if is_token_expired(&e) => {
+                    let _ = sender.send(Event::TokenExpired);
+                    break;
+                }
+                Err(e) => {
+                    warn!("Polling session state failed: {}", e);
+                    continue;
+                }
+            };
+
+            if mode == PollMode::Follow {
+                let next = Position::from(&state);
+                let (actions, events) = diff_positions(position, next);
+                for action in actions {
+                    execute(controller.clone(), action, &sender).await;
+                }
+                for event in events {
+                    let _ = sender.send(event);
+                }
+                position = next;
+            }
+
+            // 初回は基準にするだけ。それまでの投票状況は`fetch_session_state`で取得済み
+            if let Some(previous_state) = &previous_state {
+                for event in diff_votes(previous_state, &state) {
+                    let _ = sender.send(event);
+                }
+            }
+            previous_state = Some(state);
+        }
+    });
+
+    PollHandle { shutdown_tx }
+}
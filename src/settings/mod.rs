@@ -0,0 +1,174 @@
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, Context, Result};
+use log::warn;
+use serde::{Deserialize, Serialize};
+
+use crate::controller::{ControllerBackend, KeyProfile};
+use crate::net::NetworkSettings;
+use crate::paths::{config_dir, write_private, write_private_file};
+
+/// 接続フォームに入れる内容一式。会場ごとに名前を付けて保存する
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(default)]
+pub struct ConnectionProfile {
+    pub name: String,
+    pub primary_server_address: String,
+    pub agent_name: String,
+    pub controller_backend: ControllerBackend,
+    pub key_profile: KeyProfile,
+    pub network: NetworkSettings,
+}
+
+/// GUIから変更される設定。手で編集する`config.toml`とは別に保存する
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(default)]
+pub struct Settings {
+    pub profiles: Vec<ConnectionProfile>,
+    pub default_profile: Option<String>,
+    /// プロファイルを使わずに接続したときの内容
+    pub last_used: Option<ConnectionProfile>,
+    pub display: DisplaySettings,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum ThemeChoice {
+    #[default]
+    Light,
+    Dark,
+    CatppuccinLatte,
+    CatppuccinFrappe,
+    CatppuccinMacchiato,
+    CatppuccinMocha,
+    /// 暗いステージ袖でも読めるよう、黒地に白文字で線を太くする
+    HighContrast,
+}
+
+impl ThemeChoice {
+    pub const ALL: [ThemeChoice; 7] = [
+        ThemeChoice::Light,
+        ThemeChoice::Dark,
+        ThemeChoice::CatppuccinLatte,
+        ThemeChoice::CatppuccinFrappe,
+        ThemeChoice::CatppuccinMacchiato,
+        ThemeChoice::CatppuccinMocha,
+        ThemeChoice::HighContrast,
+    ];
+
+    pub fn label(&self) -> &'static str {
+        match self {
+            ThemeChoice::Light => "Light",
+            ThemeChoice::Dark => "Dark",
+            ThemeChoice::CatppuccinLatte => "Catppuccin Latte",
+            ThemeChoice::CatppuccinFrappe => "Catppuccin Frappé",
+            ThemeChoice::CatppuccinMacchiato => "Catppuccin Macchiato",
+            ThemeChoice::CatppuccinMocha => "Catppuccin Mocha",
+            ThemeChoice::HighContrast => "High contrast",
+        }
+    }
+}
+
+pub const MIN_FONT_SCALE: f32 = 0.75;
+pub const MAX_FONT_SCALE: f32 = 2.5;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default)]
+pub struct DisplaySettings {
+    pub theme: ThemeChoice,
+    pub font_scale: f32,
+}
+
+impl Default for DisplaySettings {
+    fn default() -> Self {
+        DisplaySettings {
+            theme: ThemeChoice::default(),
+            font_scale: 1.0,
+        }
+    }
+}
+
+/// エクスポートしたファイルの形式。各PCに配って取り込む
+#[derive(Serialize, Deserialize, Debug)]
+struct ProfileBundle {
+    profiles: Vec<ConnectionProfile>,
+    default_profile: Option<String>,
+}
+
+impl Settings {
+    pub fn profile(&self, name: &str) -> Option<&ConnectionProfile> {
+        self.profiles.iter().find(|profile| profile.name == name)
+    }
+
+    pub fn default_profile(&self) -> Option<&ConnectionProfile> {
+        self.default_profile
+            .as_deref()
+            .and_then(|name| self.profile(name))
+    }
+
+    /// 同じ名前があれば置き換える
+    pub fn upsert_profile(&mut self, profile: ConnectionProfile) {
+        match self.profiles.iter_mut().find(|p| p.name == profile.name) {
+            Some(existing) => *existing = profile,
+            None => self.profiles.push(profile),
+        }
+    }
+
+    pub fn remove_profile(&mut self, name: &str) {
+        self.profiles.retain(|profile| profile.name != name);
+        if self.default_profile.as_deref() == Some(name) {
+            self.default_profile = None;
+        }
+    }
+
+    pub fn export_profiles(&self, path: &Path) -> Result<()> {
+        let bundle = ProfileBundle {
+            profiles: self.profiles.clone(),
+            default_profile: self.default_profile.clone(),
+        };
+        // プロキシのURLに認証情報が入っていることがあるので、他のユーザーから読めなくする
+        write_private_file(path, &serde_json::to_vec_pretty(&bundle)?)
+            .with_context(|| format!("Failed to write {}", path.display()))
+    }
+
+    /// 取り込んだプロファイルの数を返す。既定のプロファイルも取り込み元に合わせる
+    pub fn import_profiles(&mut self, path: &Path) -> Result<usize> {
+        let contents =
+            std::fs::read(path).with_context(|| format!("Failed to read {}", path.display()))?;
+        let bundle: ProfileBundle = serde_json::from_slice(&contents)
+            .with_context(|| format!("Invalid profile file {}", path.display()))?;
+
+        let count = bundle.profiles.len();
+        for profile in bundle.profiles {
+            self.upsert_profile(profile);
+        }
+        if bundle.default_profile.is_some() {
+            self.default_profile = bundle.default_profile;
+        }
+        Ok(count)
+    }
+}
+
+fn settings_path() -> Option<PathBuf> {
+    config_dir().map(|dir| dir.join("settings.json"))
+}
+
+pub fn load() -> Settings {
+    let Some(path) = settings_path() else {
+        return Settings::default();
+    };
+    let Ok(contents) = std::fs::read(&path) else {
+        return Settings::default();
+    };
+    serde_json::from_slice(&contents).unwrap_or_else(|e| {
+        warn!("Ignoring broken settings {}: {}", path.display(), e);
+        Settings::default()
+    })
+}
+
+pub fn save(settings: &Settings) -> Result<()> {
+    let path = settings_path().ok_or_else(|| anyhow!("No config directory available"))?;
+    // プロキシの認証情報を含むことがあるので所有者だけが読めるようにする
+    write_private(&path, &serde_json::to_vec_pretty(settings)?)?;
+    Ok(())
+}
@@ -0,0 +1,82 @@
+use std::net::SocketAddr;
+
+use anyhow::{Context, Result};
+use axum::extract::State;
+use axum::http::header;
+use axum::response::IntoResponse;
+use axum::routing::get;
+use axum::{Json, Router};
+use log::{error, info, warn};
+use serde_json::{json, Value};
+
+use crate::agent::AgentClient;
+
+/// `/status`と`/metrics`を返すHTTPサーバーを`runtime`上で開く。開けなくてもエージェント自体は動かし続ける
+pub fn spawn(runtime: &tokio::runtime::Handle, listen: SocketAddr, client: AgentClient) {
+    if !listen.ip().is_loopback() {
+        warn!(
+            "Status server is listening on non-loopback address {}",
+            listen
+        );
+    }
+    runtime.spawn(async move {
+        if let Err(e) = serve(listen, client).await {
+            error!("Status server stopped: {:#}", e);
+        }
+    });
+}
+
+pub async fn serve(listen: SocketAddr, client: AgentClient) -> Result<()> {
+    let app = Router::new()
+        .route("/status", get(status))
+        .route("/metrics", get(metrics))
+        .with_state(client);
+    let listener = tokio::net::TcpListener::bind(listen)
+        .await
+        .with_context(|| format!("failed to bind {}", listen))?;
+    info!("Status server listening on http://{}", listen);
+    axum::serve(listener, app).await?;
+    Ok(())
+}
+
+async fn status(State(client): State<AgentClient>) -> Json<Value> {
+    let snapshot = client.snapshot();
+    let metrics = client.metrics();
+    let session = snapshot.session_info.as_ref().map(|info| {
+        json!({
+            "id": snapshot.session_id,
+            "title": info.title,
+        })
+    });
+    let page_id = snapshot
+        .pages
+        .get(snapshot.current_slide_index)
+        .map(|page| page.page_id.clone());
+    Json(json!({
+        "session": session,
+        "connection": {
+            "phase": snapshot.phase.label(),
+            "connected": snapshot.phase.is_connected(),
+            "ws_connected": snapshot.ws_connected,
+            "reauth_required": snapshot.reauth_required,
+            "rtt_ms": metrics.last_rtt().map(|rtt| rtt.as_secs_f64() * 1000.0),
+        },
+        "position": {
+            "page": snapshot.current_slide_index,
+            "step": snapshot.current_step,
+            "page_id": page_id,
+            "total_pages": snapshot.total_slide_count,
+        },
+        "backend": {
+            "kind": snapshot.controller_backend,
+            "paused": snapshot.actuation_paused,
+            "actuation_failures": metrics.actuation_failures(),
+            "last_error": metrics.last_actuation_error(),
+        },
+    }))
+}
+
+async fn metrics(State(client): State<AgentClient>) -> impl IntoResponse {
+    let body = client.metrics().render(&client.snapshot());
+    ([(header::CONTENT_TYPE, "text/plain; version=0.0.4")], body)
+}
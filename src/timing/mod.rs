@@ -0,0 +1,85 @@
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+/// 発表の経過時間と、ページごとの滞在時間を測る
+#[derive(Debug, Clone, Default)]
+pub struct PresentationTimer {
+    started_at: Option<Instant>,
+    current_page: Option<usize>,
+    page_entered_at: Option<Instant>,
+    /// 表示中のページの分は含まない
+    dwell: HashMap<usize, Duration>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Pace {
+    OnTrack,
+    Warning,
+    Over,
+}
+
+impl PresentationTimer {
+    pub fn start(&mut self, page_index: usize) {
+        if self.started_at.is_none() {
+            self.started_at = Some(Instant::now());
+            self.enter_page(page_index);
+        }
+    }
+
+    pub fn reset(&mut self) {
+        *self = PresentationTimer::default();
+    }
+
+    pub fn on_page_changed(&mut self, page_index: usize) {
+        if self.started_at.is_none() {
+            self.start(page_index);
+            return;
+        }
+        if self.current_page == Some(page_index) {
+            return;
+        }
+        if let (Some(page), Some(entered_at)) = (self.current_page, self.page_entered_at) {
+            *self.dwell.entry(page).or_default() += entered_at.elapsed();
+        }
+        self.enter_page(page_index);
+    }
+
+    fn enter_page(&mut self, page_index: usize) {
+        self.current_page = Some(page_index);
+        self.page_entered_at = Some(Instant::now());
+    }
+
+    pub fn elapsed(&self) -> Duration {
+        self.started_at
+            .map(|started_at| started_at.elapsed())
+            .unwrap_or_default()
+    }
+
+    /// 表示中の分も含めた、そのページの合計滞在時間
+    pub fn dwell(&self, page_index: usize) -> Duration {
+        let past = self.dwell.get(&page_index).copied().unwrap_or_default();
+        match (self.current_page, self.page_entered_at) {
+            (Some(page), Some(entered_at)) if page == page_index => past + entered_at.elapsed(),
+            _ => past,
+        }
+    }
+}
+
+pub fn pace(actual: Duration, target: Duration, warning_ratio: f32) -> Pace {
+    if actual > target {
+        Pace::Over
+    } else if actual.as_secs_f32() >= target.as_secs_f32() * warning_ratio {
+        Pace::Warning
+    } else {
+        Pace::OnTrack
+    }
+}
+
+pub fn format_duration(duration: Duration) -> String {
+    let secs = duration.as_secs();
+    if secs >= 3600 {
+        format!("{}:{:02}:{:02}", secs / 3600, secs / 60 % 60, secs % 60)
+    } else {
+        format!("{}:{:02}", secs / 60, secs % 60)
+    }
+}
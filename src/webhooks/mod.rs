@@ -0,0 +1,313 @@
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use anyhow::{anyhow, Result};
+use chrono::{DateTime, Local};
+use hmac::{Hmac, Mac};
+use log::{error, info, warn};
+use serde_json::json;
+use sha2::Sha256;
+use tokio::sync::{broadcast, watch};
+
+use crate::agent::AgentClient;
+use crate::config::WebhookConfig;
+use crate::models::events::Event;
+use crate::net::{self, NetworkSettings};
+
+/// GUIに残す配信結果の上限。古いものから捨てる
+const DELIVERY_LOG_CAPACITY: usize = 200;
+/// 再送の間隔。1回ごとに倍にし、この上限で止める
+const INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DeliveryOutcome {
+    Delivered {
+        status: u16,
+    },
+    /// 失敗したが、まだ送り直す
+    Retrying {
+        error: String,
+    },
+    /// 送り直しても届かなかった、または送り直しても無駄な応答だった
+    Failed {
+        error: String,
+    },
+}
+
+/// 1回の送信の結果
+#[derive(Debug, Clone)]
+pub struct Delivery {
+    pub timestamp: DateTime<Local>,
+    pub url: String,
+    pub event: &'static str,
+    pub attempt: u32,
+    pub outcome: DeliveryOutcome,
+}
+
+/// 動いているWebhookの設定の差し替えと、配信結果の読み出しのための窓口
+#[derive(Clone)]
+pub struct WebhookHandle {
+    webhooks: Arc<watch::Sender<Arc<Vec<WebhookConfig>>>>,
+    deliveries: Arc<Mutex<VecDeque<Delivery>>>,
+}
+
+impl WebhookHandle {
+    /// 設定ファイルを読み直したときに呼ぶ。次のイベントから新しい設定で動く
+    pub fn update(&self, webhooks: Vec<WebhookConfig>) {
+        self.webhooks.send_replace(Arc::new(webhooks));
+    }
+
+    /// 古い順
+    pub fn deliveries(&self) -> Vec<Delivery> {
+        self.deliveries.lock().unwrap().iter().cloned().collect()
+    }
+
+    pub fn clear_deliveries(&self) {
+        self.deliveries.lock().unwrap().clear();
+    }
+}
+
+/// 中核のイベントを購読し、合うWebhookへ送り続ける。`on_delivery`は送信を試みるたびに呼ぶ。
+/// 送信には`network`のプロキシと証明書を使う
+pub fn spawn(
+    runtime: &tokio::runtime::Handle,
+    client: AgentClient,
+    webhooks: Vec<WebhookConfig>,
+    network: &NetworkSettings,
+    on_delivery: impl Fn() + Send + Sync + 'static,
+) -> WebhookHandle {
+    let (webhooks_tx, webhooks_rx) = watch::channel(Arc::new(webhooks));
+    let handle = WebhookHandle {
+        webhooks: Arc::new(webhooks_tx),
+        deliveries: Arc::default(),
+    };
+    // プロキシを通さずに送ってしまわないよう、作れなければ配信しない
+    let http = match net::build_http_client(network) {
+        Ok(http) => http,
+        Err(e) => {
+            error!("Webhooks disabled: {:#}", e);
+            return handle;
+        }
+    };
+    let sender = Sender {
+        http,
+        deliveries: handle.deliveries.clone(),
+        on_delivery: Arc::new(on_delivery),
+    };
+    let events = client.events();
+    runtime.spawn(run(client, events, webhooks_rx, sender));
+    handle
+}
+
+#[derive(Clone)]
+struct Sender {
+    http: reqwest::Client,
+    deliveries: Arc<Mutex<VecDeque<Delivery>>>,
+    on_delivery: Arc<dyn Fn() + Send + Sync>,
+}
+
+async fn run(
+    client: AgentClient,
+    mut events: broadcast::Receiver<Event>,
+    webhooks: watch::Receiver<Arc<Vec<WebhookConfig>>>,
+    sender: Sender,
+) {
+    loop {
+        let event = match events.recv().await {
+            Ok(event) => event,
+            Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                warn!("Webhooks fell behind; skipped {} events", skipped);
+                continue;
+            }
+            Err(broadcast::error::RecvError::Closed) => return,
+        };
+        let webhooks = webhooks.borrow().clone();
+        let matching: Vec<WebhookConfig> = webhooks
+            .iter()
+            .filter(|webhook| webhook.events.iter().any(|name| name == event.name()))
+            .cloned()
+            .collect();
+        if matching.is_empty() {
+            continue;
+        }
+
+        let timestamp = Local::now();
+        let body = json!({
+            "event": event.name(),
+            "timestamp": timestamp,
+            "session_id": client.snapshot().session_id,
+            "data": event,
+        })
+        .to_string();
+        // 送り直しで後続のイベントが遅れないよう、1件ずつ別のタスクで送る
+        for webhook in matching {
+            let sender = sender.clone();
+            let body = body.clone();
+            let event = event.name();
+            tokio::spawn(async move {
+                sender
+                    .deliver(&webhook, event, timestamp.timestamp(), &body)
+                    .await
+            });
+        }
+    }
+}
+
+impl Sender {
+    async fn deliver(
+        &self,
+        webhook: &WebhookConfig,
+        event: &'static str,
+        timestamp: i64,
+        body: &str,
+    ) {
+        let mut backoff = INITIAL_BACKOFF;
+        for attempt in 1..=webhook.max_attempts {
+            let result = self.post(webhook, event, timestamp, body).await;
+            let outcome = match result {
+                Ok(status) => {
+                    info!("Delivered {} to {}", event, webhook.url);
+                    DeliveryOutcome::Delivered { status }
+                }
+                Err(Failure { error, retryable }) => {
+                    warn!(
+                        "Webhook {} failed (attempt {}): {:#}",
+                        webhook.url, attempt, error
+                    );
+                    if retryable && attempt < webhook.max_attempts {
+                        DeliveryOutcome::Retrying {
+                            error: format!("{:#}", error),
+                        }
+                    } else {
+                        DeliveryOutcome::Failed {
+                            error: format!("{:#}", error),
+                        }
+                    }
+                }
+            };
+            let retrying = matches!(outcome, DeliveryOutcome::Retrying { .. });
+            self.record(Delivery {
+                timestamp: Local::now(),
+                url: webhook.url.clone(),
+                event,
+                attempt,
+                outcome,
+            });
+            if !retrying {
+                return;
+            }
+            tokio::time::sleep(backoff).await;
+            backoff = (backoff * 2).min(MAX_BACKOFF);
+        }
+    }
+
+    async fn post(
+        &self,
+        webhook: &WebhookConfig,
+        event: &str,
+        timestamp: i64,
+        body: &str,
+    ) -> Result<u16, Failure> {
+        let mut request = self
+            .http
+            .post(&webhook.url)
+            .timeout(Duration::from_secs(webhook.timeout_secs))
+            .header(reqwest::header::CONTENT_TYPE, "application/json")
+            .header("X-Desktop-Agent-Event", event)
+            .header("X-Desktop-Agent-Timestamp", timestamp.to_string());
+        if let Some(secret) = &webhook.secret {
+            request = request.header("X-Desktop-Agent-Signature", sign(secret, timestamp, body));
+        }
+        let response = request
+            .body(body.to_owned())
+            .send()
+            .await
+            .map_err(|e| Failure::retryable(e.into()))?;
+
+        let status = response.status();
+        if status.is_success() {
+            return Ok(status.as_u16());
+        }
+        Err(Failure {
+            error: anyhow!("HTTP {}", status),
+            retryable: is_retryable(status),
+        })
+    }
+
+    fn record(&self, delivery: Delivery) {
+        let mut deliveries = self.deliveries.lock().unwrap();
+        if deliveries.len() == DELIVERY_LOG_CAPACITY {
+            deliveries.pop_front();
+        }
+        deliveries.push_back(delivery);
+        drop(deliveries);
+        (self.on_delivery)();
+    }
+}
+
+struct Failure {
+    error: anyhow::Error,
+    retryable: bool,
+}
+
+impl Failure {
+    fn retryable(error: anyhow::Error) -> Self {
+        Failure {
+            error,
+            retryable: true,
+        }
+    }
+}
+
+/// 受け手の不調や混雑なら時間を置けば通ることがあるが、それ以外は何度送っても同じ
+fn is_retryable(status: reqwest::StatusCode) -> bool {
+    status.is_server_error() || status == reqwest::StatusCode::TOO_MANY_REQUESTS
+}
+
+/// `sha256=<hex>`。受け手が古い配信の再送を見分けられるよう、時刻も署名に含める。
+/// 署名する内容は`<timestamp>.<body>`
+fn sign(secret: &str, timestamp: i64, body: &str) -> String {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC accepts any key length");
+    mac.update(timestamp.to_string().as_bytes());
+    mac.update(b".");
+    mac.update(body.as_bytes());
+    format!("sha256={}", hex::encode(mac.finalize().into_bytes()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use reqwest::StatusCode;
+
+    #[test]
+    fn signs_timestamp_and_body() {
+        // `printf '1700000000.{"event":"slide_changed"}' | openssl dgst -sha256 -hmac secret`と同じ値
+        assert_eq!(
+            sign("secret", 1_700_000_000, r#"{"event":"slide_changed"}"#),
+            "sha256=10f591243b1982121907a74a93a71d0d1f1922dbddeb623dfd822aa353df3ba0"
+        );
+    }
+
+    #[test]
+    fn retries_only_server_errors_and_rate_limits() {
+        let cases = [
+            (StatusCode::INTERNAL_SERVER_ERROR, true),
+            (StatusCode::BAD_GATEWAY, true),
+            (StatusCode::SERVICE_UNAVAILABLE, true),
+            (StatusCode::GATEWAY_TIMEOUT, true),
+            (StatusCode::TOO_MANY_REQUESTS, true),
+            (StatusCode::BAD_REQUEST, false),
+            (StatusCode::UNAUTHORIZED, false),
+            (StatusCode::FORBIDDEN, false),
+            (StatusCode::NOT_FOUND, false),
+            (StatusCode::GONE, false),
+            (StatusCode::PERMANENT_REDIRECT, false),
+        ];
+        for (status, retryable) in cases {
+            assert_eq!(is_retryable(status), retryable, "{}", status);
+        }
+    }
+}
@@ -1,32 +1,66 @@
-use crate::models::websocket::{RegisterAgentMessage, RegisterAgentMessageData, WsEvent};
+use crate::audit::{AuditLog, CommandSource, Outcome};
+use crate::controller::{execute, Action, Controller};
+use crate::metrics::Metrics;
+use crate::models::websocket::{
+    RegisterAgentMessage, RegisterAgentMessageData, WsEnvelope, WsEvent, WsRequest,
+};
+use crate::net::{connect_ws, NetworkSettings};
 use anyhow::Result;
-use enigo::{Direction::Click, Key, Keyboard, Settings};
 use futures_util::{SinkExt, StreamExt};
-use log::{error, warn};
+use sequence::{SequenceCheck, SequenceTracker};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 use tokio_tungstenite::tungstenite;
+use tokio_tungstenite::tungstenite::protocol::frame::coding::CloseCode;
+
+pub mod sequence;
+
+/// 往復時間を測るためのPingの間隔
+const PING_INTERVAL: Duration = Duration::from_secs(15);
 
 pub struct WsHandle {
     shutdown_tx: tokio::sync::oneshot::Sender<()>,
+    outgoing_tx: tokio::sync::mpsc::UnboundedSender<String>,
 }
 
 impl WsHandle {
+    /// サーバーへ操作を依頼する。結果は通常のコマンドとして返ってくる
+    pub fn send(&self, request: &WsRequest) -> Result<(), anyhow::Error> {
+        let message = serde_json::to_string(request)?;
+        self.outgoing_tx
+            .send(message)
+            .map_err(|_| anyhow::anyhow!("WebSocket is closed"))
+    }
+
     pub fn shutdown(self) {
         log::info!("Shutting down WebSocket");
         let _ = self.shutdown_tx.send(());
     }
 }
 
+/// 接続先のセッションと登録に使う情報
+pub struct WsTarget<'a> {
+    pub base_url: &'a str,
+    pub session_id: &'a str,
+    pub token: &'a str,
+    pub agent_name: &'a str,
+}
+
 pub async fn run_websocket(
-    base_url: &str,
-    session_id: &str,
-    token: &str,
-    agent_name: &str,
-    sender: std::sync::mpsc::Sender<crate::models::events::Event>,
+    network: &NetworkSettings,
+    target: WsTarget<'_>,
+    controller: Arc<dyn Controller>,
+    tracker: Arc<Mutex<SequenceTracker>>,
+    sender: tokio::sync::mpsc::UnboundedSender<crate::models::events::Event>,
+    metrics: Arc<Metrics>,
+    audit: Arc<AuditLog>,
 ) -> Result<WsHandle, anyhow::Error> {
-    let ws_base_url = base_url.replace("http", "ws");
-    let (ws_stream, _) =
-        tokio_tungstenite::connect_async(format!("{}/agent?sessionId={}", ws_base_url, session_id))
-            .await?;
+    let ws_base_url = target.base_url.replace("http", "ws");
+    let ws_stream = connect_ws(
+        &format!("{}/agent?sessionId={}", ws_base_url, target.session_id),
+        network,
+    )
+    .await?;
     sender
         .send(crate::models::events::Event::ConnectionEstablished)
         .unwrap();
@@ -36,23 +70,105 @@ pub async fn run_websocket(
     let register_message = serde_json::to_string(&RegisterAgentMessage {
         msg_type: "REGIST_AGENT",
         data: RegisterAgentMessageData {
-            agent_name,
+            agent_name: target.agent_name,
             agent_type: "SHOW_SLIDE_DESKTOP",
-            token,
+            token: target.token,
         },
     })?;
     sink.send(tungstenite::Message::text(register_message))
         .await?;
     let (shutdown_tx, shutdown_rx) = tokio::sync::oneshot::channel();
+    let (outgoing_tx, mut outgoing_rx) = tokio::sync::mpsc::unbounded_channel::<String>();
+    // Pingの中身にはこの時点からの経過時間を入れ、Pongで戻ってきた値との差を往復時間とする
+    let epoch = Instant::now();
+    let session_id = target.session_id.to_owned();
 
     tokio::spawn(async move {
         tokio::select! {
             _ = async {
                 while let Some(Ok(msg)) = stream.next().await {
-                    if let Ok(event) = serde_json::from_str::<WsEvent>(&msg.to_string()) {
-                        handle_event(event, &sender).await;
-                    } else {
+                    if let tungstenite::Message::Pong(payload) = &msg {
+                        if let Some(rtt) = round_trip_time(epoch, payload) {
+                            metrics.record_rtt(rtt);
+                        }
+                        continue;
+                    }
+                    if let tungstenite::Message::Close(Some(frame)) = &msg {
+                        if is_auth_rejection(frame.code) {
+                            log::warn!("WebSocket closed by server: token rejected");
+                            let _ = sender.send(crate::models::events::Event::TokenExpired);
+                        }
+                        // 接続はもう使えないので、どちらの場合も切断として知らせる
+                        break;
+                    }
+                    let Ok(envelope) = serde_json::from_str::<WsEnvelope>(&msg.to_string()) else {
                         log::warn!("Received invalid message: {:?}", msg);
+                        continue;
+                    };
+                    let check = tracker
+                        .lock()
+                        .unwrap()
+                        .check(envelope.seq, envelope.timestamp);
+                    match check {
+                        SequenceCheck::Accept => {
+                            let received_at = Instant::now();
+                            let action = handle_event(envelope.event, &controller, &sender).await;
+                            metrics.record_command(action, received_at.elapsed());
+                        }
+                        SequenceCheck::Duplicate | SequenceCheck::Stale => {
+                            let detail = format!("{:?}", check).to_lowercase();
+                            audit.record(
+                                &session_id,
+                                CommandSource::Websocket,
+                                action_of(&envelope.event),
+                                Outcome::Dropped,
+                                Some(&detail),
+                            );
+                            log::warn!(
+                                "Dropping {:?} command (seq: {:?}, timestamp: {:?})",
+                                check,
+                                envelope.seq,
+                                envelope.timestamp
+                            );
+                        }
+                        SequenceCheck::Gap { expected, received } => {
+                            // 抜けたコマンドの上に相対操作を重ねるとずれるので、適用せず状態を取り直す
+                            audit.record(
+                                &session_id,
+                                CommandSource::Websocket,
+                                action_of(&envelope.event),
+                                Outcome::Dropped,
+                                Some("gap"),
+                            );
+                            log::warn!(
+                                "Command stream gap: expected seq {}, received {}",
+                                expected,
+                                received
+                            );
+                            let _ = sender.send(crate::models::events::Event::ResyncRequired);
+                        }
+                    }
+                }
+                log::warn!("WebSocket connection lost");
+                let _ = sender.send(crate::models::events::Event::ConnectionLost);
+            } => {},
+            _ = async {
+                let mut ping_interval = tokio::time::interval(PING_INTERVAL);
+                loop {
+                    // 送信側が閉じても受信とPingは続ける
+                    tokio::select! {
+                        Some(message) = outgoing_rx.recv() => {
+                            if let Err(e) = sink.send(tungstenite::Message::text(message)).await {
+                                log::warn!("Failed to send WebSocket request: {}", e);
+                            }
+                        }
+                        _ = ping_interval.tick() => {
+                            let payload = (epoch.elapsed().as_micros() as u64).to_be_bytes();
+                            let ping = tungstenite::Message::Ping(payload.to_vec().into());
+                            if let Err(e) = sink.send(ping).await {
+                                log::warn!("Failed to send WebSocket ping: {}", e);
+                            }
+                        }
                     }
                 }
             } => {},
@@ -63,63 +179,54 @@ pub async fn run_websocket(
         sink.close().await.ok();
     });
 
-    Ok(WsHandle { shutdown_tx })
+    Ok(WsHandle {
+        shutdown_tx,
+        outgoing_tx,
+    })
 }
 
-async fn handle_event(
-    event: WsEvent,
-    sender: &std::sync::mpsc::Sender<crate::models::events::Event>,
-) {
-    match event {
-        WsEvent::ChangeCurrentPage { data } => {
-            tokio::task::spawn_blocking({
-                move || {
-                    let mut enigo = enigo::Enigo::new(&Settings::default()).unwrap();
-
-                    let new_page_chars: Vec<char> =
-                        data.new_page_index.to_string().chars().collect();
-
-                    for c in new_page_chars {
-                        enigo.key(Key::Unicode(c), Click).unwrap();
-                    }
+/// 1008(Policy Violation)と4401はトークン失効による切断として扱う
+fn is_auth_rejection(code: CloseCode) -> bool {
+    matches!(u16::from(code), 1008 | 4401)
+}
 
-                    enigo.key(Key::Return, Click).unwrap();
-                }
-            });
+fn round_trip_time(epoch: Instant, payload: &[u8]) -> Option<Duration> {
+    let sent_micros = u64::from_be_bytes(payload.try_into().ok()?);
+    epoch
+        .elapsed()
+        .checked_sub(Duration::from_micros(sent_micros))
+}
 
-            sender
-                .send(crate::models::events::Event::SlideChanged {
-                    new_page_index: data.new_page_index,
-                })
-                .unwrap();
-        }
-        WsEvent::TriggerNextStep { data } => {
-            tokio::task::spawn_blocking({
-                move || {
-                    let mut enigo = enigo::Enigo::new(&Settings::default()).unwrap();
-                    enigo.key(Key::RightArrow, Click).unwrap();
-                }
-            });
-            sender
-                .send(crate::models::events::Event::StepChanged {
-                    new_page_index: data.new_page_index,
-                    new_step_index: data.new_step_index,
-                })
-                .unwrap();
-        }
-        WsEvent::TriggerPrevStep { data } => {
-            tokio::task::spawn_blocking({
-                move || {
-                    let mut enigo = enigo::Enigo::new(&Settings::default()).unwrap();
-                    enigo.key(Key::LeftArrow, Click).unwrap();
-                }
-            });
-            sender
-                .send(crate::models::events::Event::StepChanged {
-                    new_page_index: data.new_page_index,
-                    new_step_index: data.new_step_index,
-                })
-                .unwrap();
-        }
+/// コマンドに対応するデスクトップ側の操作
+fn action_of(event: &WsEvent) -> Action {
+    match event {
+        WsEvent::ChangeCurrentPage { data } => Action::ChangePage(data.new_page_index),
+        WsEvent::TriggerNextStep { .. } => Action::NextStep,
+        WsEvent::TriggerPrevStep { .. } => Action::PrevStep,
     }
 }
+
+/// コマンドを実行し、状態の変化を中核に伝える。実行した操作を返す
+async fn handle_event(
+    event: WsEvent,
+    controller: &Arc<dyn Controller>,
+    sender: &tokio::sync::mpsc::UnboundedSender<crate::models::events::Event>,
+) -> Action {
+    let action = action_of(&event);
+    execute(controller.clone(), action, sender).await;
+    let changed = match event {
+        WsEvent::ChangeCurrentPage { data } => crate::models::events::Event::SlideChanged {
+            new_page_index: data.new_page_index,
+        },
+        WsEvent::TriggerNextStep { data } => crate::models::events::Event::StepChanged {
+            new_page_index: data.new_page_index,
+            new_step_index: data.new_step_index,
+        },
+        WsEvent::TriggerPrevStep { data } => crate::models::events::Event::StepChanged {
+            new_page_index: data.new_page_index,
+            new_step_index: data.new_step_index,
+        },
+    };
+    sender.send(changed).unwrap();
+    action
+}
@@ -0,0 +1,96 @@
+/// 受信したコマンドの通し番号を追跡し、欠落・重複を見つける
+#[derive(Debug, Default)]
+pub struct SequenceTracker {
+    last_seq: Option<u64>,
+    last_timestamp: Option<i64>,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum SequenceCheck {
+    Accept,
+    Duplicate,
+    Stale,
+    Gap { expected: u64, received: u64 },
+}
+
+impl SequenceTracker {
+    /// 通し番号があればそれで、なければタイムスタンプで判定する。どちらもなければ常に受け入れる
+    pub fn check(&mut self, seq: Option<u64>, timestamp: Option<i64>) -> SequenceCheck {
+        let result = match (seq, self.last_seq) {
+            (Some(seq), Some(last)) if seq == last => SequenceCheck::Duplicate,
+            (Some(seq), Some(last)) if seq < last => SequenceCheck::Stale,
+            (Some(seq), Some(last)) if seq > last + 1 => SequenceCheck::Gap {
+                expected: last + 1,
+                received: seq,
+            },
+            (Some(_), _) => SequenceCheck::Accept,
+            (None, _) => match (timestamp, self.last_timestamp) {
+                (Some(timestamp), Some(last)) if timestamp == last => SequenceCheck::Duplicate,
+                (Some(timestamp), Some(last)) if timestamp < last => SequenceCheck::Stale,
+                _ => SequenceCheck::Accept,
+            },
+        };
+
+        // 欠落の場合も番号は進める。状態は再同期で取り直す
+        if matches!(result, SequenceCheck::Accept | SequenceCheck::Gap { .. }) {
+            if seq.is_some() {
+                self.last_seq = seq;
+            }
+            if timestamp.is_some() {
+                self.last_timestamp = timestamp;
+            }
+        }
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn accepts_consecutive_sequence_numbers() {
+        let mut tracker = SequenceTracker::default();
+        assert_eq!(tracker.check(Some(1), None), SequenceCheck::Accept);
+        assert_eq!(tracker.check(Some(2), None), SequenceCheck::Accept);
+    }
+
+    #[test]
+    fn detects_duplicate_and_stale_sequence_numbers() {
+        let mut tracker = SequenceTracker::default();
+        tracker.check(Some(5), None);
+        assert_eq!(tracker.check(Some(5), None), SequenceCheck::Duplicate);
+        assert_eq!(tracker.check(Some(3), None), SequenceCheck::Stale);
+        assert_eq!(tracker.check(Some(6), None), SequenceCheck::Accept);
+    }
+
+    #[test]
+    fn reports_gap_and_moves_past_it() {
+        let mut tracker = SequenceTracker::default();
+        tracker.check(Some(1), None);
+        assert_eq!(
+            tracker.check(Some(4), None),
+            SequenceCheck::Gap {
+                expected: 2,
+                received: 4
+            }
+        );
+        assert_eq!(tracker.check(Some(5), None), SequenceCheck::Accept);
+    }
+
+    #[test]
+    fn falls_back_to_timestamps_without_sequence_numbers() {
+        let mut tracker = SequenceTracker::default();
+        assert_eq!(tracker.check(None, Some(100)), SequenceCheck::Accept);
+        assert_eq!(tracker.check(None, Some(100)), SequenceCheck::Duplicate);
+        assert_eq!(tracker.check(None, Some(90)), SequenceCheck::Stale);
+        assert_eq!(tracker.check(None, Some(110)), SequenceCheck::Accept);
+    }
+
+    #[test]
+    fn accepts_commands_without_sequence_or_timestamp() {
+        let mut tracker = SequenceTracker::default();
+        assert_eq!(tracker.check(None, None), SequenceCheck::Accept);
+        assert_eq!(tracker.check(None, None), SequenceCheck::Accept);
+    }
+}